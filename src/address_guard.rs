@@ -0,0 +1,433 @@
+//! Refuse outbound connections to addresses which are not publicly routable,
+//! so that clients can not make the relay reach loopback, private network or
+//! cloud metadata services by naming them as gateways.
+//!
+//! Addresses are checked after DNS resolution, right before connecting, so a
+//! gateway whose name resolves to a public address when probed and to a
+//! private one when relayed to is still refused. CIDR ranges on the deny list
+//! of [`GatewayLists`] are refused the same way.
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http::uri::{Authority, Scheme};
+use http::Uri;
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpStream;
+use tower_service::Service;
+use tracing::warn;
+
+use crate::error::{BoxError, Error};
+use crate::gateway_lists::GatewayLists;
+use crate::gateway_uri::GatewayUri;
+use crate::upstream_proxy::UpstreamProxies;
+
+/// Decides which addresses the relay may connect to.
+#[derive(Debug, Clone)]
+pub(crate) struct AddressGuard {
+    allow_private: bool,
+    /// The default gateway and the gateways pooled with it are configured by
+    /// the operator, so they may be private, e.g. a gateway running on the
+    /// same host.
+    trusted: Vec<Authority>,
+    lists: GatewayLists,
+}
+
+impl AddressGuard {
+    pub(crate) fn new(default_gateway: &GatewayUri, allow_private: bool) -> Self {
+        Self {
+            allow_private,
+            trusted: vec![default_gateway.authority().clone()],
+            lists: GatewayLists::default(),
+        }
+    }
+
+    /// A guard which permits every address.
+    pub(crate) fn allow_all() -> Self {
+        Self { allow_private: true, trusted: Vec::new(), lists: GatewayLists::default() }
+    }
+
+    /// Also trust `gateway`, which the operator configured.
+    pub(crate) fn trust(mut self, gateway: &GatewayUri) -> Self {
+        self.trusted.push(gateway.authority().clone());
+        self
+    }
+
+    /// Also refuse addresses in CIDR ranges denied by `lists`, even for
+    /// trusted gateways, since denied gateways take precedence.
+    pub(crate) fn deny_listed(mut self, lists: GatewayLists) -> Self {
+        self.lists = lists;
+        self
+    }
+
+    /// Whether `ip` may be connected to, for a `trusted` gateway or not.
+    fn permits(&self, ip: IpAddr, trusted: bool) -> bool {
+        (trusted || self.allow_private || is_public(ip)) && !self.lists.denies_ip(ip)
+    }
+
+    fn trusts(&self, host: &str, port: u16) -> bool {
+        self.trusted.iter().any(|trusted| {
+            trusted.host().eq_ignore_ascii_case(host) && trusted.port_u16() == Some(port)
+        })
+    }
+
+    /// The addresses `host` resolved to which may be connected to. Fails if
+    /// there were addresses but none of them may be connected to.
+    fn filter(
+        &self,
+        host: &str,
+        trusted: bool,
+        addrs: impl IntoIterator<Item = SocketAddr>,
+    ) -> io::Result<Vec<SocketAddr>> {
+        let (permitted, refused): (Vec<_>, Vec<_>) =
+            addrs.into_iter().partition(|addr| self.permits(addr.ip(), trusted));
+        if permitted.is_empty() && !refused.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} does not resolve to a permitted address", host),
+            ));
+        }
+        Ok(permitted)
+    }
+
+    /// Resolve a gateway to an address which may be connected to.
+    pub(crate) async fn resolve(&self, gateway: &GatewayUri) -> Result<SocketAddr, Error> {
+        let addrs = gateway.to_socket_addrs().await.map_err(|e| {
+            warn!("Failed to resolve gateway {}: {}", gateway.authority(), e);
+            Error::BadGateway
+        })?;
+        let authority = gateway.authority();
+        let trusted = self.trusts(authority.host(), authority.port_u16().unwrap_or(443));
+        match self.filter(authority.host(), trusted, addrs) {
+            Ok(addrs) => addrs.into_iter().next().ok_or(Error::NotFound),
+            Err(_) => Err(Error::Denied),
+        }
+    }
+
+    /// The addresses `host` resolves to which may be connected to, for
+    /// connecting through an upstream proxy which does not resolve names.
+    pub(crate) async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let addrs = tokio::net::lookup_host((unbracket(host), port)).await?;
+        let permitted = self.filter(host, self.trusts(host, port), addrs)?;
+        match permitted.is_empty() {
+            true => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not resolve to any address", host),
+            )),
+            false => Ok(permitted),
+        }
+    }
+
+    /// Refuse `host` if it is an IP address which may not be connected to,
+    /// or a `localhost` name, which always resolves to loopback. Other names
+    /// are checked once they are resolved, which a proxy that resolves names
+    /// does out of sight of the relay.
+    pub(crate) fn check_unresolved_host(&self, host: &str, port: u16) -> io::Result<()> {
+        let ip = match unbracket(host).parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) if is_localhost(host) => Ipv4Addr::LOCALHOST.into(),
+            Err(_) => return Ok(()),
+        };
+        match self.permits(ip, self.trusts(host, port)) {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not a permitted address", host),
+            )),
+        }
+    }
+
+    /// An HTTP connector which only connects to permitted addresses, through
+    /// the upstream proxies if any apply.
+    pub(crate) fn connector(&self, proxies: UpstreamProxies) -> GuardedConnector {
+        let resolver =
+            |trusted| GuardedResolver { inner: GaiResolver::new(), guard: self.clone(), trusted };
+        let mut guarded = HttpConnector::new_with_resolver(resolver(false));
+        guarded.enforce_http(false);
+        let mut trusted = HttpConnector::new_with_resolver(resolver(true));
+        trusted.enforce_http(false);
+        GuardedConnector { guarded, trusted, guard: self.clone(), proxies, connect_timeout: None }
+    }
+}
+
+/// Resolves host names like [`GaiResolver`], dropping refused addresses.
+#[derive(Debug, Clone)]
+pub(crate) struct GuardedResolver {
+    inner: GaiResolver,
+    guard: AddressGuard,
+    /// Whether names are resolved for trusted gateways, whose addresses are
+    /// only refused if they are denied.
+    trusted: bool,
+}
+
+impl Service<Name> for GuardedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let (resolving, guard, trusted) =
+            (self.inner.call(name.clone()), self.guard.clone(), self.trusted);
+        Box::pin(
+            async move { Ok(guard.filter(name.as_str(), trusted, resolving.await?)?.into_iter()) },
+        )
+    }
+}
+
+/// An [`HttpConnector`] which only connects to permitted addresses, which for
+/// trusted gateways are all but denied ones. IP address hosts are checked
+/// here since they are connected to without consulting the resolver.
+#[derive(Debug, Clone)]
+pub(crate) struct GuardedConnector {
+    guarded: HttpConnector<GuardedResolver>,
+    trusted: HttpConnector<GuardedResolver>,
+    guard: AddressGuard,
+    proxies: UpstreamProxies,
+    connect_timeout: Option<Duration>,
+}
+
+impl GuardedConnector {
+    pub(crate) fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.guarded.set_connect_timeout(timeout);
+        self.trusted.set_connect_timeout(timeout);
+        self.connect_timeout = timeout;
+    }
+}
+
+impl Service<Uri> for GuardedConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.guarded.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        let host = dst.host().unwrap_or_default();
+        let default_port = if dst.scheme() == Some(&Scheme::HTTP) { 80 } else { 443 };
+        let port = dst.port_u16().unwrap_or(default_port);
+        if let Err(refused) = self.guard.check_unresolved_host(host, port) {
+            return Box::pin(async move { Err(refused.into()) });
+        }
+        if let Some(proxy) = self.proxies.proxy_for(host, port).cloned() {
+            let (guard, host, timeout) =
+                (self.guard.clone(), host.to_string(), self.connect_timeout);
+            return Box::pin(async move {
+                let connecting = async {
+                    let host = proxy.destination(&guard, &host, port).await?;
+                    proxy.connect(&host, port).await
+                };
+                let stream = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, connecting)
+                        .await
+                        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??,
+                    None => connecting.await?,
+                };
+                Ok(TokioIo::new(stream))
+            });
+        }
+        if self.guard.trusts(host, port) {
+            let connecting = self.trusted.call(dst);
+            return Box::pin(async move { connecting.await.map_err(Into::into) });
+        }
+        let connecting = self.guarded.call(dst);
+        Box::pin(async move { connecting.await.map_err(Into::into) })
+    }
+}
+
+/// Whether `host` is `localhost` or a name under it, which resolve to
+/// loopback addresses (RFC 6761).
+fn is_localhost(host: &str) -> bool {
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    host == "localhost" || host.ends_with(".localhost")
+}
+
+fn unbracket(host: &str) -> &str { host.trim_start_matches('[').trim_end_matches(']') }
+
+/// Whether an address is publicly routable, as opposed to e.g. loopback,
+/// private, link-local, shared or reserved addresses.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(a == 0 // "this" network
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local() // including cloud metadata services at 169.254.169.254
+        || (a == 100 && (b & 0b1100_0000) == 64) // shared address space 100.64.0.0/10
+        || (a == 192 && b == 0 && c == 0) // IETF protocol assignments
+        || ip.is_documentation()
+        || (a == 198 && (b & 0b1111_1110) == 18) // benchmarking 198.18.0.0/15
+        || ip.is_multicast()
+        || a >= 240) // reserved, including broadcast
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    if let Some(ip) = ip.to_ipv4_mapped() {
+        return is_public_v4(ip);
+    }
+    let segments = ip.segments();
+    if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        // NAT64 translates to the embedded IPv4 address
+        return is_public_v4(embedded_v4(segments[6], segments[7]));
+    }
+    if segments[0] == 0x2002 {
+        // 6to4 tunnels to the embedded IPv4 address
+        return is_public_v4(embedded_v4(segments[1], segments[2]));
+    }
+    if segments[..2] == [0x2001, 0] {
+        // Teredo tunnels through the server's IPv4 address to the client's,
+        // which is embedded inverted
+        return is_public_v4(embedded_v4(segments[2], segments[3]))
+            && is_public_v4(embedded_v4(!segments[6], !segments[7]));
+    }
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || segments[..6] == [0; 6] // deprecated IPv4 compatible addresses
+        || (segments[0] & 0xfe00) == 0xfc00 // unique local fc00::/7
+        || (segments[0] & 0xffc0) == 0xfe80 // link-local fe80::/10
+        || (segments[0] & 0xffc0) == 0xfec0 // deprecated site-local fec0::/10
+        || (segments[0] == 0x2001 && segments[1] == 0xdb8) // documentation
+        || ip.is_multicast())
+}
+
+fn embedded_v4(high: u16, low: u16) -> Ipv4Addr {
+    Ipv4Addr::from((u32::from(high) << 16) | u32::from(low))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_is_public() {
+        for public in [
+            "1.1.1.1",
+            "8.8.8.8",
+            "2606:4700::1111",
+            "::ffff:1.1.1.1",
+            "64:ff9b::101:101",
+            "2002:101:101::1",
+            "2001:0:4136:e378:8000:63bf:fefe:fefe",
+        ] {
+            assert!(is_public(public.parse().unwrap()), "{} should be public", public);
+        }
+        for private in [
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "192.0.0.8",
+            "198.18.0.1",
+            "224.0.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "::127.0.0.1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a00:1",
+            "2002:7f00:1::",
+            "2002:a00:1::1",
+            "2002:a9fe:a9fe::",
+            "2001:0:4136:e378:8000:63bf:80ff:fffe",
+            "2001:0:a00:1:8000:63bf:fefe:fefe",
+            "fd00:ec2::254",
+            "fe80::1",
+            "2001:db8::1",
+            "ff02::1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{} should not be public", private);
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let guard = AddressGuard::new(&GatewayUri::from_static("http://localhost:8080"), false);
+        let loopback = SocketAddr::from_str("127.0.0.1:80").unwrap();
+        let public = SocketAddr::from_str("1.1.1.1:80").unwrap();
+        assert_eq!(guard.filter("example.com", false, [loopback, public]).unwrap(), vec![public]);
+        assert_eq!(
+            guard.filter("example.com", false, [loopback]).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied,
+            "names which only resolve to private addresses should be refused"
+        );
+        assert!(guard.filter("example.com", false, []).unwrap().is_empty());
+        assert_eq!(guard.filter("localhost", true, [loopback]).unwrap(), vec![loopback]);
+        assert!(guard.trusts("LOCALHOST", 8080), "the default gateway should be trusted");
+        assert!(!guard.trusts("localhost", 22), "other ports on its host should not be trusted");
+
+        let guard = AddressGuard::new(&GatewayUri::from_static("https://payjo.in"), true);
+        assert_eq!(guard.filter("example.com", false, [loopback]).unwrap(), vec![loopback]);
+    }
+
+    #[tokio::test]
+    async fn test_denied_cidrs() {
+        let lists = GatewayLists::new([], ["127.0.0.0/8", "1.1.1.0/24"]).unwrap();
+        let guard = AddressGuard::new(&GatewayUri::from_static("http://localhost:8080"), true)
+            .deny_listed(lists.clone());
+        let loopback = SocketAddr::from_str("127.0.0.1:80").unwrap();
+        let public = SocketAddr::from_str("1.1.1.1:80").unwrap();
+        let other = SocketAddr::from_str("8.8.8.8:80").unwrap();
+        assert_eq!(guard.filter("example.com", false, [public, other]).unwrap(), vec![other]);
+        assert_eq!(
+            guard.filter("localhost", true, [loopback]).unwrap_err().kind(),
+            io::ErrorKind::PermissionDenied,
+            "denied ranges should be refused even for trusted gateways"
+        );
+        assert!(guard.check_unresolved_host("1.1.1.1", 443).is_err());
+        assert!(guard.resolve(&GatewayUri::from_static("http://localhost:8080")).await.is_err());
+        assert!(
+            matches!(
+                guard.resolve(&GatewayUri::from_static("https://gateway.invalid")).await,
+                Err(Error::BadGateway)
+            ),
+            "unresolvable gateways should be a bad gateway"
+        );
+
+        let mut connector = guard.connector(UpstreamProxies::default());
+        let err = connector.call(Uri::from_static("http://localhost:1/")).await.unwrap_err();
+        assert_eq!(
+            crate::error::io_error_kind(err.as_ref()),
+            Some(io::ErrorKind::PermissionDenied)
+        );
+
+        lists.replace([], []).unwrap();
+        assert!(guard.check_unresolved_host("1.1.1.1", 443).is_ok(), "reloaded lists should apply");
+    }
+
+    #[tokio::test]
+    async fn test_connector_refuses_private_addresses() {
+        let guard = AddressGuard::new(&GatewayUri::from_static("https://payjo.in"), false);
+        let mut connector = guard.connector(UpstreamProxies::default());
+        for uri in ["http://127.0.0.1:1/", "http://[::1]:1/", "http://localhost:1/"] {
+            let err = connector.call(Uri::from_static(uri)).await.unwrap_err();
+            assert_eq!(
+                crate::error::io_error_kind(err.as_ref()),
+                Some(io::ErrorKind::PermissionDenied),
+                "{} should be refused before connecting",
+                uri
+            );
+        }
+    }
+}
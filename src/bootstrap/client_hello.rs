@@ -0,0 +1,228 @@
+//! Just enough TLS record parsing to find the server name a client asks for
+//! in its ClientHello, see RFC 8446 section 4.1.2 and RFC 6066 section 3.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Upper bound for the records buffered while waiting for a ClientHello.
+/// Post-quantum key shares make ClientHellos a few KiB, well below this.
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+const CONTENT_TYPE_HANDSHAKE: u8 = 22;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 1;
+const EXTENSION_SERVER_NAME: u16 = 0;
+const NAME_TYPE_HOST_NAME: u8 = 0;
+
+/// Read a TLS ClientHello from `client` and check that it is for `host`.
+/// Returns the bytes read, which must be forwarded to the gateway.
+///
+/// Clients do not send a server name for IP address hosts, so for those the
+/// ClientHello must not name a server.
+pub(crate) async fn read_client_hello<R>(client: &mut R, host: &str) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let server_name = loop {
+        if let Some(server_name) = parse_server_name(&buf)? {
+            break server_name;
+        }
+        if buf.len() >= MAX_CLIENT_HELLO_SIZE {
+            return Err(invalid("ClientHello too large"));
+        }
+        if client.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    };
+
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let expected = host.parse::<std::net::IpAddr>().is_err().then_some(host);
+    match (server_name.as_deref(), expected) {
+        (Some(name), Some(host)) if name.eq_ignore_ascii_case(host) => Ok(buf),
+        (None, None) => Ok(buf),
+        (name, _) => Err(invalid(&format!("ClientHello for {:?} instead of {}", name, host))),
+    }
+}
+
+/// The server name of the ClientHello at the start of `buf`, `None` if more
+/// bytes are needed and `Some(None)` if it names no server.
+fn parse_server_name(buf: &[u8]) -> io::Result<Option<Option<String>>> {
+    // a handshake message may be fragmented across several records
+    let mut handshake = Vec::new();
+    let mut records = Reader(buf);
+    while records.0.len() >= 5 {
+        if records.u8()? != CONTENT_TYPE_HANDSHAKE {
+            return Err(invalid("not a TLS handshake"));
+        }
+        let _legacy_version = records.u16()?;
+        let len = usize::from(records.u16()?);
+        if records.0.len() < len {
+            return Ok(None);
+        }
+        handshake.extend_from_slice(records.take(len)?);
+
+        let mut message = Reader(&handshake);
+        if message.0.len() < 4 {
+            continue;
+        }
+        if message.u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+            return Err(invalid("not a ClientHello"));
+        }
+        let len = message.u24()?;
+        if message.0.len() >= len {
+            return server_name(Reader(message.take(len)?)).map(Some);
+        }
+    }
+    Ok(None)
+}
+
+fn server_name(mut client_hello: Reader) -> io::Result<Option<String>> {
+    let _legacy_version = client_hello.u16()?;
+    let _random = client_hello.take(32)?;
+    let len = usize::from(client_hello.u8()?);
+    let _legacy_session_id = client_hello.take(len)?;
+    let len = usize::from(client_hello.u16()?);
+    let _cipher_suites = client_hello.take(len)?;
+    let len = usize::from(client_hello.u8()?);
+    let _legacy_compression_methods = client_hello.take(len)?;
+    if client_hello.0.is_empty() {
+        return Ok(None);
+    }
+
+    let len = usize::from(client_hello.u16()?);
+    let mut extensions = Reader(client_hello.take(len)?);
+    while !extensions.0.is_empty() {
+        let extension_type = extensions.u16()?;
+        let len = usize::from(extensions.u16()?);
+        let mut data = Reader(extensions.take(len)?);
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let len = usize::from(data.u16()?);
+        let mut names = Reader(data.take(len)?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = usize::from(names.u16()?);
+            let name = names.take(len)?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                let name = std::str::from_utf8(name).map_err(|_| invalid("invalid server name"))?;
+                return Ok(Some(name.to_string()));
+            }
+        }
+    }
+    Ok(None)
+}
+
+fn invalid(reason: &str) -> io::Error { io::Error::new(io::ErrorKind::InvalidData, reason) }
+
+/// Reads big endian integers and length prefixed vectors.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(invalid("truncated ClientHello"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> { Ok(self.take(1)?[0]) }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> io::Result<usize> {
+        let bytes = self.take(3)?;
+        Ok(usize::from(bytes[0]) << 16 | usize::from(bytes[1]) << 8 | usize::from(bytes[2]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A TLS 1.3 style ClientHello handshake message, naming `server_name`.
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = vec![0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]; // supported_versions
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let list_len = (name.len() + 3) as u16;
+            extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+            extensions.extend_from_slice(&(list_len + 2).to_be_bytes());
+            extensions.extend_from_slice(&list_len.to_be_bytes());
+            extensions.push(NAME_TYPE_HOST_NAME);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0x2a; 32]);
+        body.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut message = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        message.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        message.extend_from_slice(&body);
+        message
+    }
+
+    /// Wrap a handshake message in records of at most `fragment_len` bytes.
+    fn records(message: &[u8], fragment_len: usize) -> Vec<u8> {
+        message
+            .chunks(fragment_len)
+            .flat_map(|fragment| {
+                let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+                record.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+                record.extend_from_slice(fragment);
+                record
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_server_name() {
+        let hello = records(&client_hello(Some("payjo.in")), 1024);
+        assert_eq!(parse_server_name(&hello).unwrap(), Some(Some("payjo.in".to_string())));
+        for len in [0, 4, 5, hello.len() - 1] {
+            assert_eq!(parse_server_name(&hello[..len]).unwrap(), None, "{} bytes", len);
+        }
+
+        let fragmented = records(&client_hello(Some("payjo.in")), 16);
+        assert_eq!(
+            parse_server_name(&fragmented).unwrap(),
+            Some(Some("payjo.in".to_string())),
+            "fragmented ClientHellos should be reassembled"
+        );
+        assert_eq!(parse_server_name(&records(&client_hello(None), 1024)).unwrap(), Some(None));
+
+        assert!(parse_server_name(b"GET / HTTP/1.1\r\n\r\n").is_err(), "plaintext HTTP");
+        assert!(parse_server_name(b"SSH-2.0-OpenSSH_9.6\r\n").is_err(), "SSH");
+        let mut server_hello = client_hello(Some("payjo.in"));
+        server_hello[0] = 2;
+        assert!(parse_server_name(&records(&server_hello, 1024)).is_err(), "not a ClientHello");
+    }
+
+    #[tokio::test]
+    async fn test_read_client_hello() {
+        let hello = records(&client_hello(Some("PAYJO.IN")), 1024);
+        let read = read_client_hello(&mut &hello[..], "payjo.in").await.unwrap();
+        assert_eq!(read, hello, "bytes read must be forwarded");
+
+        let err = read_client_hello(&mut &hello[..], "evil.example").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "server name must match the gateway");
+
+        let no_name = records(&client_hello(None), 1024);
+        assert!(read_client_hello(&mut &no_name[..], "127.0.0.1").await.is_ok());
+        assert!(read_client_hello(&mut &no_name[..], "payjo.in").await.is_err());
+        assert!(read_client_hello(&mut &hello[..], "[::1]").await.is_err());
+
+        let truncated = &hello[..hello.len() - 1];
+        let err = read_client_hello(&mut &truncated[..], "payjo.in").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,51 +1,51 @@
-use std::net::SocketAddr;
-
 use http_body_util::combinators::BoxBody;
 use hyper::body::{Bytes, Incoming};
 use hyper::upgrade::Upgraded;
 use hyper::{Method, Request, Response};
 use hyper_util::rt::TokioIo;
-use tokio::net::TcpStream;
 use tracing::{error, instrument};
 
+use super::{Destination, Relayed, TunnelLimits};
 use crate::error::Error;
-use crate::{empty, GatewayUri};
+use crate::{empty, GatewayUri, RelayConfig};
 
 pub(crate) fn is_connect_request(req: &Request<Incoming>) -> bool {
     Method::CONNECT == req.method()
 }
 
-#[instrument]
+#[instrument(skip(config))]
 pub(crate) async fn try_upgrade(
     req: Request<Incoming>,
     gateway_origin: GatewayUri,
+    config: &RelayConfig,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
-    let addr = gateway_origin
-        .to_socket_addr()
-        .await
-        .map_err(|e| Error::InternalServerError(Box::new(e)))?
-        .ok_or_else(|| Error::NotFound)?;
+    let destination = Destination::resolve(&gateway_origin, config).await?;
+    let host = gateway_origin.authority().host().to_string();
+    let limits = config.bootstrap.tunnel_limits;
 
-    tokio::task::spawn(async move {
+    config.tunnels.spawn("connect", |relayed| async move {
         match hyper::upgrade::on(req).await {
-            Ok(upgraded) => {
-                if let Err(e) = tunnel(upgraded, addr).await {
+            Ok(upgraded) =>
+                if let Err(e) = tunnel(upgraded, destination, host, limits, &relayed).await {
                     error!("server io error: {}", e);
-                };
-            }
+                },
             Err(e) => error!("upgrade error: {}", e),
         }
-    });
+    })?;
 
     Ok(Response::new(empty()))
 }
 
-/// Create a TCP connection to host:port, build a tunnel between the connection and
-/// the upgraded connection
-#[instrument]
-async fn tunnel(upgraded: Upgraded, addr: SocketAddr) -> std::io::Result<()> {
-    let mut server = TcpStream::connect(addr).await?;
-    let mut upgraded = TokioIo::new(upgraded);
-    let (_, _) = tokio::io::copy_bidirectional(&mut upgraded, &mut server).await?;
+/// Connect to the gateway, build a tunnel between the connection and
+/// the upgraded connection.
+#[instrument(skip(relayed))]
+async fn tunnel(
+    upgraded: Upgraded,
+    destination: Destination,
+    host: String,
+    limits: TunnelLimits,
+    relayed: &Relayed,
+) -> std::io::Result<()> {
+    limits.tunnel_tls("connect", &mut TokioIo::new(upgraded), &destination, &host, relayed).await?;
     Ok(())
 }
@@ -1,31 +1,592 @@
+use std::fmt;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
 use http_body_util::combinators::BoxBody;
 use hyper::body::{Bytes, Incoming};
+#[cfg(feature = "ws-bootstrap")]
+use hyper::{Method, Version};
 use hyper::{Request, Response};
-use tracing::instrument;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::{debug, info, instrument, warn};
 
 use crate::error::Error;
-use crate::GatewayUri;
+use crate::limits::Gauge;
+use crate::metrics::Metrics;
+use crate::timeouts::UpstreamTimeouts;
+use crate::{GatewayUri, RelayConfig, UpstreamProxy};
 
+mod client_hello;
 #[cfg(feature = "connect-bootstrap")]
 pub mod connect;
 
 #[cfg(feature = "ws-bootstrap")]
 pub mod ws;
 
+/// Which of the compiled in bootstrap mechanisms are enabled at runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct BootstrapConfig {
+    #[cfg(feature = "connect-bootstrap")]
+    pub(crate) connect: bool,
+    #[cfg(feature = "ws-bootstrap")]
+    pub(crate) websocket: bool,
+    #[cfg(feature = "ws-bootstrap")]
+    pub(crate) websocket_policy: ws::WebSocketPolicy,
+    pub(crate) tunnel_limits: TunnelLimits,
+}
+
+impl Default for BootstrapConfig {
+    fn default() -> Self {
+        Self {
+            #[cfg(feature = "connect-bootstrap")]
+            connect: true,
+            #[cfg(feature = "ws-bootstrap")]
+            websocket: true,
+            #[cfg(feature = "ws-bootstrap")]
+            websocket_policy: ws::WebSocketPolicy::default(),
+            tunnel_limits: TunnelLimits::default(),
+        }
+    }
+}
+
+/// Limits on each bootstrap tunnel. `None` disables a limit.
+///
+/// Bootstrapping only takes one TLS handshake and one `ohttp-keys` request,
+/// so tunnels are closed once a limit is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TunnelLimits {
+    /// Connecting to the gateway, or through an upstream proxy to it.
+    pub connect_timeout: Option<Duration>,
+    /// How long a tunnel may go without relaying any bytes.
+    pub idle_timeout: Option<Duration>,
+    /// How long a tunnel may stay open.
+    pub max_lifetime: Option<Duration>,
+    /// Bytes relayed from the client to the gateway.
+    pub max_bytes_to_gateway: Option<u64>,
+    /// Bytes relayed from the gateway to the client.
+    pub max_bytes_to_client: Option<u64>,
+}
+
+impl Default for TunnelLimits {
+    fn default() -> Self {
+        Self {
+            connect_timeout: UpstreamTimeouts::RELAY.connect,
+            idle_timeout: Some(Duration::from_secs(30)),
+            max_lifetime: Some(Duration::from_secs(120)),
+            max_bytes_to_gateway: Some(64 * 1024),
+            max_bytes_to_client: Some(256 * 1024),
+        }
+    }
+}
+
+/// How long clients are given to send their TLS ClientHello through a tunnel.
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a tunnel was closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Closed {
+    Finished,
+    IdleTimeout,
+    MaxLifetime,
+    MaxBytesToGateway,
+    MaxBytesToClient,
+}
+
+impl fmt::Display for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Finished => write!(f, "both sides finished"),
+            Self::IdleTimeout => write!(f, "idle timeout"),
+            Self::MaxLifetime => write!(f, "maximum lifetime reached"),
+            Self::MaxBytesToGateway => write!(f, "byte limit to gateway reached"),
+            Self::MaxBytesToClient => write!(f, "byte limit to client reached"),
+        }
+    }
+}
+
+/// Bytes a tunnel has relayed so far. They are counted as they are relayed,
+/// so that tunnels which fail or are cut off by shutdown are accounted for.
+#[derive(Debug, Default)]
+pub(crate) struct Relayed {
+    to_gateway: AtomicU64,
+    to_client: AtomicU64,
+}
+
+impl Relayed {
+    fn to_gateway(&self) -> u64 { self.to_gateway.load(Ordering::Relaxed) }
+
+    fn to_client(&self) -> u64 { self.to_client.load(Ordering::Relaxed) }
+}
+
+/// Where a tunnel connects to its gateway.
+#[derive(Debug, Clone)]
+pub(crate) enum Destination {
+    Direct(SocketAddr),
+    Proxied { proxy: UpstreamProxy, host: String, port: u16 },
+}
+
+impl Destination {
+    /// Resolve a gateway to an address which may be connected to, or to the
+    /// upstream proxy connections to it go through.
+    pub(crate) async fn resolve(gateway: &GatewayUri, config: &RelayConfig) -> Result<Self, Error> {
+        let authority = gateway.authority();
+        let port = authority.port_u16().unwrap_or(443);
+        let Some(proxy) = config.upstream_proxies.proxy_for(authority.host(), port) else {
+            return Ok(Self::Direct(config.address_guard.resolve(gateway).await?));
+        };
+        let host = proxy.destination(&config.address_guard, authority.host(), port).await.map_err(
+            |e| match e.kind() {
+                std::io::ErrorKind::PermissionDenied => Error::Denied,
+                std::io::ErrorKind::NotFound => Error::NotFound,
+                _ => {
+                    warn!("Failed to resolve gateway {}: {}", authority, e);
+                    Error::BadGateway
+                }
+            },
+        )?;
+        Ok(Self::Proxied { proxy: proxy.clone(), host, port })
+    }
+
+    async fn connect(&self, timeout: Option<Duration>) -> std::io::Result<TcpStream> {
+        let connect = async {
+            match self {
+                Self::Direct(addr) => TcpStream::connect(addr).await,
+                Self::Proxied { proxy, host, port } => proxy.connect(host, *port).await,
+            }
+        };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, connect)
+                .await
+                .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))?,
+            None => connect.await,
+        }
+    }
+}
+
+impl TunnelLimits {
+    /// Connect to the gateway at `dst` and relay between it and `client`,
+    /// counting the bytes relayed in `relayed`.
+    ///
+    /// The tunnel is only built if the client starts a TLS handshake with
+    /// `host`, so that the relay can not be used as a generic TCP proxy.
+    pub(crate) async fn tunnel_tls<C>(
+        &self,
+        kind: &str,
+        client: &mut C,
+        dst: &Destination,
+        host: &str,
+        relayed: &Relayed,
+    ) -> std::io::Result<Closed>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let client_hello = tokio::time::timeout(
+            CLIENT_HELLO_TIMEOUT,
+            client_hello::read_client_hello(client, host),
+        )
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+        let mut gateway = dst.connect(self.connect_timeout).await?;
+        gateway.write_all(&client_hello).await?;
+        relayed.to_gateway.fetch_add(client_hello.len() as u64, Ordering::Relaxed);
+        self.copy_bidirectional(kind, client, &mut gateway, relayed).await
+    }
+
+    /// Relay bytes between `client` and `gateway` like
+    /// [`tokio::io::copy_bidirectional`] until both sides finish or a limit
+    /// is reached, adding the bytes relayed to `relayed`. Returns why the
+    /// tunnel was closed.
+    pub(crate) async fn copy_bidirectional<C, G>(
+        &self,
+        kind: &str,
+        client: &mut C,
+        gateway: &mut G,
+        relayed: &Relayed,
+    ) -> std::io::Result<Closed>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        G: AsyncRead + AsyncWrite + Unpin,
+    {
+        let deadline = self.max_lifetime.map(|lifetime| Instant::now() + lifetime);
+        let (mut client_buf, mut gateway_buf) = ([0; 8 * 1024], [0; 8 * 1024]);
+        let (mut client_open, mut gateway_open) = (true, true);
+        // writes are bounded too, so a peer which stops reading can not hold
+        // the tunnel open by stalling them
+        let relay = async {
+            loop {
+                if !client_open && !gateway_open {
+                    return Ok::<_, std::io::Error>(Closed::Finished);
+                }
+                tokio::select! {
+                    read = client.read(&mut client_buf), if client_open => match read? {
+                        0 => {
+                            client_open = false;
+                            if self.write(gateway.shutdown()).await?.is_none() {
+                                return Ok(Closed::IdleTimeout);
+                            }
+                        }
+                        n => {
+                            if self
+                                .max_bytes_to_gateway
+                                .is_some_and(|max| relayed.to_gateway() + n as u64 > max)
+                            {
+                                return Ok(Closed::MaxBytesToGateway);
+                            }
+                            let write = write_counted(gateway, &client_buf[..n], &relayed.to_gateway);
+                            if self.write(write).await?.is_none() {
+                                return Ok(Closed::IdleTimeout);
+                            }
+                        }
+                    },
+                    read = gateway.read(&mut gateway_buf), if gateway_open => match read? {
+                        0 => {
+                            gateway_open = false;
+                            if self.write(client.shutdown()).await?.is_none() {
+                                return Ok(Closed::IdleTimeout);
+                            }
+                        }
+                        n => {
+                            if self
+                                .max_bytes_to_client
+                                .is_some_and(|max| relayed.to_client() + n as u64 > max)
+                            {
+                                return Ok(Closed::MaxBytesToClient);
+                            }
+                            let write = write_counted(client, &gateway_buf[..n], &relayed.to_client);
+                            if self.write(write).await?.is_none() {
+                                return Ok(Closed::IdleTimeout);
+                            }
+                        }
+                    },
+                    _ = sleep(self.idle_timeout) => return Ok(Closed::IdleTimeout),
+                }
+            }
+        };
+        let closed = match deadline {
+            Some(deadline) =>
+                tokio::time::timeout_at(deadline, relay).await.unwrap_or(Ok(Closed::MaxLifetime)),
+            None => relay.await,
+        }?;
+        match closed {
+            Closed::Finished => debug!("Closing {} tunnel: {}", kind, closed),
+            _ => info!("Closing {} tunnel: {}", kind, closed),
+        }
+        Ok(closed)
+    }
+
+    /// Write to one side of a tunnel, or give up with `None` if the write
+    /// does not complete within the idle timeout.
+    async fn write<F>(&self, write: F) -> std::io::Result<Option<()>>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        match self.idle_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, write).await.ok().transpose(),
+            None => write.await.map(Some),
+        }
+    }
+}
+
+/// Write all of `buf` and flush it, adding each chunk to `counter` once it
+/// was written, so that a stalled write only counts what got through.
+async fn write_counted<W>(
+    writer: &mut W,
+    mut buf: &[u8],
+    counter: &AtomicU64,
+) -> std::io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while !buf.is_empty() {
+        let n = writer.write(buf).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        buf = &buf[n..];
+    }
+    writer.flush().await
+}
+
+async fn sleep(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => tokio::time::sleep(duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Upgraded bootstrap tunnels, which outlive the HTTP connection they were
+/// upgraded from and so must be tracked separately for shutdown.
+#[derive(Debug)]
+pub(crate) struct Tunnels {
+    tracker: TaskTracker,
+    shutdown: CancellationToken,
+    open: Gauge,
+    metrics: Metrics,
+}
+
+impl Tunnels {
+    pub(crate) fn new(open: Gauge, metrics: Metrics) -> Self {
+        Self { tracker: TaskTracker::new(), shutdown: CancellationToken::new(), open, metrics }
+    }
+
+    /// Spawn a tunnel of the given `kind`, unless too many are open already.
+    /// Must be called before responding to the upgrade request, so it can
+    /// still be refused. The tunnel counts the bytes it relays in the given
+    /// [`Relayed`].
+    pub(crate) fn spawn<T, F>(&self, kind: &'static str, tunnel: T) -> Result<(), Error>
+    where
+        T: FnOnce(Arc<Relayed>) -> F,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let open = self.open.try_acquire()?;
+        let shutdown = self.shutdown.clone();
+        let metrics = self.metrics.clone();
+        let relayed = Arc::new(Relayed::default());
+        let tunnel = tunnel(relayed.clone());
+        metrics.tunnel_opened(kind);
+        self.tracker.spawn(async move {
+            let _open = open;
+            tokio::select! {
+                _ = tunnel => {}
+                _ = shutdown.cancelled() => debug!("Closing tunnel for shutdown"),
+            }
+            metrics.tunnel_closed(kind, relayed.to_gateway(), relayed.to_client());
+        });
+        Ok(())
+    }
+
+    /// Stop tracking new tunnels and wait for the open ones to close.
+    pub(crate) async fn wait(&self) {
+        self.tracker.close();
+        self.tracker.wait().await
+    }
+
+    /// Close all open tunnels, returning how many were cut off.
+    pub(crate) async fn close(&self) -> usize {
+        self.tracker.close();
+        let open = self.tracker.len();
+        self.shutdown.cancel();
+        self.tracker.wait().await;
+        open
+    }
+}
+
 #[instrument]
 pub(crate) async fn handle_ohttp_keys(
     mut req: Request<Incoming>,
     gateway_origin: GatewayUri,
+    config: &RelayConfig,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
     #[cfg(feature = "connect-bootstrap")]
-    if connect::is_connect_request(&req) {
-        return connect::try_upgrade(req, gateway_origin).await;
+    if config.bootstrap.connect && connect::is_connect_request(&req) {
+        return connect::try_upgrade(req, gateway_origin, config).await;
+    }
+
+    #[cfg(feature = "ws-bootstrap")]
+    if config.bootstrap.websocket && ws::is_websocket_request(&req) {
+        return ws::try_upgrade(&mut req, gateway_origin, config).await;
     }
 
+    // WebSocket upgrades rely on the HTTP/1.1 Upgrade mechanism
     #[cfg(feature = "ws-bootstrap")]
-    if ws::is_websocket_request(&req) {
-        return ws::try_upgrade(&mut req, gateway_origin).await;
+    if req.version() == Version::HTTP_2 && req.method() == Method::GET {
+        return Err(Error::BadRequest("WebSocket bootstrap requires HTTP/1.1".to_string()));
     }
 
     Err(Error::BadRequest("Not a supported proxy upgrade request".to_string()))
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::duplex;
+
+    use super::*;
+    use crate::limits::{ConcurrencyLimits, Gauges};
+
+    const NO_LIMITS: TunnelLimits = TunnelLimits {
+        connect_timeout: None,
+        idle_timeout: None,
+        max_lifetime: None,
+        max_bytes_to_gateway: None,
+        max_bytes_to_client: None,
+    };
+
+    #[tokio::test]
+    async fn test_tunnel_finishes() {
+        let (mut client, mut client_end) = duplex(1024);
+        let (mut gateway, mut gateway_end) = duplex(1024);
+        let relayed = Arc::new(Relayed::default());
+        let tunnel = tokio::spawn({
+            let relayed = relayed.clone();
+            async move {
+                NO_LIMITS
+                    .copy_bidirectional("test", &mut client_end, &mut gateway_end, &relayed)
+                    .await
+            }
+        });
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        gateway.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+        gateway.write_all(b"keys").await.unwrap();
+        gateway.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"keys");
+
+        assert_eq!(tunnel.await.unwrap().unwrap(), Closed::Finished);
+        assert_eq!((relayed.to_gateway(), relayed.to_client()), (5, 4));
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_byte_limits() {
+        for (limits, to_gateway, reason) in [
+            (
+                TunnelLimits { max_bytes_to_gateway: Some(8), ..NO_LIMITS },
+                true,
+                Closed::MaxBytesToGateway,
+            ),
+            (
+                TunnelLimits { max_bytes_to_client: Some(8), ..NO_LIMITS },
+                false,
+                Closed::MaxBytesToClient,
+            ),
+        ] {
+            let (mut client, mut client_end) = duplex(1024);
+            let (mut gateway, mut gateway_end) = duplex(1024);
+            let relayed = Arc::new(Relayed::default());
+            let tunnel = tokio::spawn({
+                let relayed = relayed.clone();
+                async move {
+                    limits
+                        .copy_bidirectional("test", &mut client_end, &mut gateway_end, &relayed)
+                        .await
+                }
+            });
+
+            let (sender, receiver) = match to_gateway {
+                true => (&mut client, &mut gateway),
+                false => (&mut gateway, &mut client),
+            };
+            sender.write_all(b"12345").await.unwrap();
+            let mut received = [0; 5];
+            receiver.read_exact(&mut received).await.unwrap();
+            sender.write_all(b"6789").await.unwrap();
+            let closed = tunnel.await.unwrap().unwrap();
+            assert_eq!(
+                relayed.to_gateway() + relayed.to_client(),
+                5,
+                "bytes past the limit are not relayed"
+            );
+            assert_eq!(closed, reason);
+            let mut rest = Vec::new();
+            receiver.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty(), "tunnel should be closed at the limit");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tunnel_timeouts() {
+        const SECOND: Duration = Duration::from_secs(1);
+        for (writes, expected, reason) in [
+            (3, 3 * SECOND + 2 * SECOND, Closed::IdleTimeout),
+            (usize::MAX, 10 * SECOND, Closed::MaxLifetime),
+        ] {
+            let limits = TunnelLimits {
+                idle_timeout: Some(2 * SECOND),
+                max_lifetime: Some(10 * SECOND),
+                ..NO_LIMITS
+            };
+            let (mut client, mut client_end) = duplex(1024);
+            let (_gateway, mut gateway_end) = duplex(1024);
+            let start = Instant::now();
+            let tunnel = tokio::spawn(async move {
+                let relayed = Relayed::default();
+                limits.copy_bidirectional("test", &mut client_end, &mut gateway_end, &relayed).await
+            });
+            tokio::spawn(async move {
+                for _ in 0..writes {
+                    tokio::time::sleep(SECOND).await;
+                    if client.write_all(b"x").await.is_err() {
+                        break;
+                    }
+                }
+            });
+            let closed = tunnel.await.unwrap().unwrap();
+            assert_eq!(start.elapsed(), expected, "{} writes a second apart", writes);
+            assert_eq!(closed, reason);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cut_off_tunnel_bytes() {
+        let metrics = Metrics::default();
+        let tunnels =
+            Tunnels::new(Gauges::from(ConcurrencyLimits::default()).tunnels, metrics.clone());
+        tunnels
+            .spawn("test", |relayed| async move {
+                relayed.to_gateway.fetch_add(5, Ordering::Relaxed);
+                relayed.to_client.fetch_add(4, Ordering::Relaxed);
+                std::future::pending().await
+            })
+            .unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(tunnels.close().await, 1);
+        let encoded = metrics.encode();
+        assert!(encoded
+            .contains(r#"ohttp_relay_tunnel_bytes_total{direction="to_gateway",kind="test"} 5"#));
+        assert!(encoded
+            .contains(r#"ohttp_relay_tunnel_bytes_total{direction="to_client",kind="test"} 4"#));
+    }
+
+    #[tokio::test]
+    async fn test_connect_timeout() {
+        // a proxy which accepts connections but never answers CONNECT
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = format!("http://{}", listener.local_addr().unwrap()).parse().unwrap();
+        let dst = Destination::Proxied { proxy, host: "gateway.example".to_string(), port: 443 };
+        let err = dst.connect(Some(Duration::from_millis(100))).await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tunnel_client_not_reading() {
+        const SECOND: Duration = Duration::from_secs(1);
+        for (limits, expected, reason) in [
+            (
+                TunnelLimits { idle_timeout: Some(2 * SECOND), ..NO_LIMITS },
+                2 * SECOND,
+                Closed::IdleTimeout,
+            ),
+            (
+                TunnelLimits { max_lifetime: Some(10 * SECOND), ..NO_LIMITS },
+                10 * SECOND,
+                Closed::MaxLifetime,
+            ),
+        ] {
+            // the client never reads, so writes to it stall once its buffer is full
+            let (_client, mut client_end) = duplex(1024);
+            let (mut gateway, mut gateway_end) = duplex(64 * 1024);
+            gateway.write_all(&[0; 64 * 1024]).await.unwrap();
+            let start = Instant::now();
+            let relayed = Relayed::default();
+            let closed = limits
+                .copy_bidirectional("test", &mut client_end, &mut gateway_end, &relayed)
+                .await
+                .unwrap();
+            assert_eq!(start.elapsed(), expected);
+            assert_eq!(closed, reason);
+            assert_eq!(relayed.to_client(), 1024, "only bytes written before stalling are counted");
+        }
+    }
+}
@@ -1,68 +1,186 @@
+use std::future::poll_fn;
 use std::io;
-use std::net::SocketAddr;
 use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
 
 use futures::{Sink, SinkExt, StreamExt};
 use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderMap, HeaderValue, ORIGIN, SEC_WEBSOCKET_PROTOCOL};
 use hyper::{Request, Response};
 use hyper_tungstenite::HyperWebsocket;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tokio_tungstenite::tungstenite::protocol::Message;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_tungstenite::tungstenite::error::ProtocolError;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::{CloseFrame, Message, WebSocketConfig};
 use tokio_tungstenite::{tungstenite, WebSocketStream};
-use tracing::{error, instrument};
+use tracing::{debug, error, instrument};
 
+use super::{Closed, Destination, Relayed, TunnelLimits};
 use crate::error::Error;
 use crate::gateway_uri::GatewayUri;
+use crate::RelayConfig;
+
+/// The WebSocket subprotocol for tunneling TLS to a gateway to bootstrap
+/// its OHTTP keys. Binary messages carry the TLS byte stream.
+pub const SUBPROTOCOL: &str = "ohttp-bootstrap.tls";
+
+/// Which WebSocket bootstrap upgrades are accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSocketPolicy {
+    /// Origins of the browser pages which may open tunnels, e.g.
+    /// `https://wallet.example`. `None` allows any origin. Requests without an
+    /// `Origin` header do not come from browser pages and are always allowed.
+    /// When origins are restricted, every client must also ask for the
+    /// [`SUBPROTOCOL`].
+    pub allowed_origins: Option<Vec<String>>,
+    /// The largest frame accepted from clients, in bytes.
+    pub max_frame_size: usize,
+    /// The largest message accepted from clients once its frames are
+    /// reassembled, in bytes.
+    pub max_message_size: usize,
+}
+
+impl Default for WebSocketPolicy {
+    fn default() -> Self {
+        Self { allowed_origins: None, max_frame_size: 64 * 1024, max_message_size: 64 * 1024 }
+    }
+}
+
+impl WebSocketPolicy {
+    fn check_origin(&self, headers: &HeaderMap) -> Result<(), Error> {
+        let (Some(allowed), Some(origin)) = (&self.allowed_origins, headers.get(ORIGIN)) else {
+            return Ok(());
+        };
+        let origin = origin.to_str().unwrap_or_default();
+        match allowed
+            .iter()
+            .any(|allowed| allowed.trim_end_matches('/').eq_ignore_ascii_case(origin))
+        {
+            true => Ok(()),
+            false => Err(Error::OriginNotAllowed),
+        }
+    }
+
+    fn websocket_config(&self) -> WebSocketConfig {
+        WebSocketConfig::default()
+            .max_frame_size(Some(self.max_frame_size))
+            .max_message_size(Some(self.max_message_size))
+    }
+
+    /// The subprotocol to echo, `None` for clients which do not ask for one
+    /// where that is allowed.
+    fn negotiate_subprotocol(&self, headers: &HeaderMap) -> Result<Option<HeaderValue>, Error> {
+        let mut offered = headers.get_all(SEC_WEBSOCKET_PROTOCOL).iter().peekable();
+        if offered.peek().is_none() && self.allowed_origins.is_none() {
+            return Ok(None);
+        }
+        match offered
+            .filter_map(|protocols| protocols.to_str().ok())
+            .flat_map(|protocols| protocols.split(','))
+            .any(|protocol| protocol.trim() == SUBPROTOCOL)
+        {
+            true => Ok(Some(HeaderValue::from_static(SUBPROTOCOL))),
+            false => Err(Error::BadRequest(format!(
+                "Unsupported WebSocket subprotocol, expected {}",
+                SUBPROTOCOL
+            ))),
+        }
+    }
+}
 
 pub(crate) fn is_websocket_request(req: &Request<Incoming>) -> bool {
     hyper_tungstenite::is_upgrade_request(req)
 }
 
-#[instrument]
+#[instrument(skip(config))]
 pub(crate) async fn try_upgrade(
     req: &mut Request<Incoming>,
     gateway_origin: GatewayUri,
+    config: &RelayConfig,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
-    let gateway_addr = gateway_origin
-        .to_socket_addr()
-        .await
-        .map_err(|e| Error::InternalServerError(Box::new(e)))?
-        .ok_or_else(|| Error::NotFound)?;
+    let policy = &config.bootstrap.websocket_policy;
+    policy.check_origin(req.headers())?;
+    let subprotocol = policy.negotiate_subprotocol(req.headers())?;
+    let destination = Destination::resolve(&gateway_origin, config).await?;
+    let host = gateway_origin.authority().host().to_string();
+    let limits = config.bootstrap.tunnel_limits;
 
-    let (res, websocket) = hyper_tungstenite::upgrade(req, None)
+    let (res, websocket) = hyper_tungstenite::upgrade(req, Some(policy.websocket_config()))
         .map_err(|e| Error::BadRequest(format!("Error upgrading to websocket: {}", e)))?;
 
-    tokio::spawn(async move {
-        if let Err(e) = serve_websocket(websocket, gateway_addr).await {
+    config.tunnels.spawn("websocket", |relayed| async move {
+        if let Err(e) = serve_websocket(websocket, destination, host, limits, &relayed).await {
             error!("Error in websocket connection: {e}");
         }
-    });
-    let (parts, body) = res.into_parts();
+    })?;
+    let (mut parts, body) = res.into_parts();
+    if let Some(subprotocol) = subprotocol {
+        parts.headers.insert(SEC_WEBSOCKET_PROTOCOL, subprotocol);
+    }
     let boxbody = body.map_err(|never| match never {}).boxed();
     Ok(Response::from_parts(parts, boxbody))
 }
 
+/// How often the relay pings WebSocket clients. Clients which have not
+/// answered by the next ping are considered dead.
+const PING_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How long to wait for a close frame to be sent before giving up on the client.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// Stream WebSocket frames from the client to the gateway server's TCP socket and vice versa.
-#[instrument]
+#[instrument(skip(relayed))]
 async fn serve_websocket(
     websocket: HyperWebsocket,
-    gateway_addr: SocketAddr,
+    destination: Destination,
+    host: String,
+    limits: TunnelLimits,
+    relayed: &Relayed,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync + 'static>> {
-    let mut tcp_stream = tokio::net::TcpStream::connect(gateway_addr).await?;
-    let mut ws_io = WsIo::new(websocket.await?);
-    let (_, _) = tokio::io::copy_bidirectional(&mut ws_io, &mut tcp_stream).await?;
+    let mut ws_io = WsIo::new(websocket.await?).with_ping_interval(PING_INTERVAL);
+    let closed = limits.tunnel_tls("websocket", &mut ws_io, &destination, &host, relayed).await;
+    let code = match &closed {
+        Ok(Closed::Finished) => CloseCode::Normal,
+        Ok(_) => CloseCode::Policy,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => CloseCode::Unsupported,
+        Err(_) => CloseCode::Error,
+    };
+    close_quietly(&mut ws_io, code).await;
+    closed?;
     Ok(())
 }
 
+/// Close the WebSocket, ignoring clients which are already gone.
+async fn close_quietly<S>(ws_io: &mut WsIo<S>, code: CloseCode)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match tokio::time::timeout(CLOSE_TIMEOUT, ws_io.close(code)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => debug!("Error closing websocket with {}: {}", code, e),
+        Err(_) => debug!("Timed out closing websocket with {}", code),
+    }
+}
+
+/// Binary WebSocket messages as a byte stream.
+///
+/// Control frames are handled while reading, text messages are rejected with
+/// [`io::ErrorKind::InvalidData`]. A close frame with a normal or going away
+/// code reads as EOF, any other close code as [`io::ErrorKind::ConnectionAborted`].
+/// Shutting down sends a normal close frame.
 pub struct WsIo<S>
 where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     ws_stream: WebSocketStream<S>,
-    read_buffer: Vec<u8>,
+    read_buffer: Bytes,
+    keepalive: Option<Interval>,
+    awaiting_pong: bool,
+    close_sent: bool,
 }
 
 impl<S> WsIo<S>
@@ -70,7 +188,64 @@ where
     S: AsyncRead + AsyncWrite + Unpin,
 {
     pub fn new(ws_stream: WebSocketStream<S>) -> Self {
-        WsIo { ws_stream, read_buffer: Vec::new() }
+        WsIo {
+            ws_stream,
+            read_buffer: Bytes::new(),
+            keepalive: None,
+            awaiting_pong: false,
+            close_sent: false,
+        }
+    }
+
+    /// Ping the peer every `interval` while reading. Reads fail with
+    /// [`io::ErrorKind::TimedOut`] if nothing was received since the
+    /// previous ping.
+    pub fn with_ping_interval(mut self, interval: Duration) -> Self {
+        let mut keepalive = tokio::time::interval_at(Instant::now() + interval, interval);
+        keepalive.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Send a close frame with `code` and flush it.
+    pub async fn close(&mut self, code: CloseCode) -> io::Result<()> {
+        poll_fn(|cx| self.poll_close_with(cx, code)).await
+    }
+
+    fn poll_close_with(&mut self, cx: &mut Context<'_>, code: CloseCode) -> Poll<io::Result<()>> {
+        if !self.close_sent {
+            ready!(Pin::new(&mut self.ws_stream).poll_ready(cx)).map_err(map_ws_error)?;
+            let frame = CloseFrame { code, reason: Default::default() };
+            match self.ws_stream.start_send_unpin(Message::Close(Some(frame))) {
+                Ok(()) | Err(tungstenite::Error::ConnectionClosed) => {}
+                Err(e) => return Poll::Ready(Err(map_ws_error(e))),
+            }
+            self.close_sent = true;
+        }
+        Pin::new(&mut self.ws_stream).poll_close(cx).map_err(map_ws_error)
+    }
+
+    /// Send a ping whenever one is due.
+    fn poll_keepalive(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        let Some(keepalive) = &mut self.keepalive else { return Ok(()) };
+        while keepalive.poll_tick(cx).is_ready() {
+            if self.awaiting_pong {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "WebSocket peer did not answer ping",
+                ));
+            }
+            // a write is in progress, the next tick pings instead
+            if Pin::new(&mut self.ws_stream).poll_ready(cx).is_pending() {
+                continue;
+            }
+            self.ws_stream.start_send_unpin(Message::Ping(Bytes::new())).map_err(map_ws_error)?;
+            if let Poll::Ready(Err(e)) = Pin::new(&mut self.ws_stream).poll_flush(cx) {
+                return Err(map_ws_error(e));
+            }
+            self.awaiting_pong = true;
+        }
+        Ok(())
     }
 }
 
@@ -84,48 +259,33 @@ where
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
         let self_mut = self.get_mut();
-
-        // If the read buffer has data, use it first.
-        if !self_mut.read_buffer.is_empty() {
-            let len = std::cmp::min(buf.remaining(), self_mut.read_buffer.len());
-            buf.put_slice(&self_mut.read_buffer[..len]);
-            self_mut.read_buffer.drain(..len);
-            return Poll::Ready(Ok(()));
-        }
-        // Otherwise, try to read a new frame.
-        match self_mut.ws_stream.poll_next_unpin(cx) {
-            Poll::Ready(Some(Ok(message))) => match message {
-                Message::Binary(data) => {
-                    self_mut.read_buffer.extend_from_slice(&data);
-                    let len = std::cmp::min(buf.remaining(), self_mut.read_buffer.len());
-                    buf.put_slice(&self_mut.read_buffer[..len]);
-                    self_mut.read_buffer.drain(..len);
-                    Poll::Ready(Ok(()))
-                }
-                Message::Ping(data) => start_send(&mut self_mut.ws_stream, Message::Pong(data)),
-                Message::Pong(_) => {
-                    // Usually, no action is needed on pong messages
-                    Poll::Pending
-                }
-                Message::Close(_) => start_send(&mut self_mut.ws_stream, Message::Close(None)),
-                _ => Poll::Pending,
-            },
-            Poll::Ready(None) => {
-                // No more messages will be received because the WebSocket stream is closed.
-                // If there's no data left in the read buffer, we signify EOF by returning Ok.
-                if self_mut.read_buffer.is_empty() {
-                    Poll::Ready(Ok(())) // Signify EOF
-                } else {
-                    // If there's still data left in the buffer, we need to return that first.
-                    // This ensures that the caller can consume all remaining data before receiving EOF.
-                    let len = std::cmp::min(buf.remaining(), self_mut.read_buffer.len());
-                    buf.put_slice(&self_mut.read_buffer[..len]);
-                    self_mut.read_buffer.drain(..len);
-                    Poll::Ready(Ok(()))
-                }
+        loop {
+            if !self_mut.read_buffer.is_empty() {
+                let len = std::cmp::min(buf.remaining(), self_mut.read_buffer.len());
+                buf.put_slice(&self_mut.read_buffer.split_to(len));
+                return Poll::Ready(Ok(()));
+            }
+            self_mut.poll_keepalive(cx)?;
+            let message = match ready!(self_mut.ws_stream.poll_next_unpin(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => return Poll::Ready(Err(map_ws_error(e))),
+                // No more messages will be received, signify EOF.
+                None => return Poll::Ready(Ok(())),
+            };
+            // anything received shows the peer is alive
+            self_mut.awaiting_pong = false;
+            match message {
+                Message::Binary(data) => self_mut.read_buffer = data,
+                // tungstenite queues pongs and close replies itself, they
+                // are flushed by the next read or write
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+                Message::Text(_) =>
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text WebSocket messages are not supported",
+                    ))),
+                Message::Close(frame) => return Poll::Ready(closed_by_peer(frame)),
             }
-            Poll::Ready(Some(Err(e))) => Poll::Ready(Err(map_ws_error(e))),
-            Poll::Pending => Poll::Pending,
         }
     }
 }
@@ -140,13 +300,12 @@ where
         data: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
         let self_mut = self.get_mut();
-        match Pin::new(&mut self_mut.ws_stream).poll_ready(cx) {
-            Poll::Ready(Ok(())) =>
-                start_send(&mut self_mut.ws_stream, Message::Binary(data.to_vec().into()))
-                    .map(|r| r.map(|_| data.len())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(map_ws_error(e))),
-            Poll::Pending => Poll::Pending,
-        }
+        ready!(Pin::new(&mut self_mut.ws_stream).poll_ready(cx)).map_err(map_ws_error)?;
+        self_mut
+            .ws_stream
+            .start_send_unpin(Message::Binary(Bytes::copy_from_slice(data)))
+            .map_err(map_ws_error)?;
+        Poll::Ready(Ok(data.len()))
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
@@ -154,17 +313,183 @@ where
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        Pin::new(&mut self.get_mut().ws_stream).poll_close(cx).map_err(map_ws_error)
+        self.get_mut().poll_close_with(cx, CloseCode::Normal)
     }
 }
 
-fn start_send(
-    ws_stream: &mut WebSocketStream<impl AsyncRead + AsyncWrite + Unpin>,
-    data: Message,
-) -> Poll<Result<(), io::Error>> {
-    Poll::Ready(ws_stream.start_send_unpin(data).map_err(map_ws_error))
+/// A close frame from the peer is EOF if the peer is done, an error otherwise.
+fn closed_by_peer(frame: Option<CloseFrame>) -> io::Result<()> {
+    match frame.map(|frame| frame.code) {
+        None | Some(CloseCode::Normal) | Some(CloseCode::Away) => Ok(()),
+        Some(code) => Err(io::Error::new(
+            io::ErrorKind::ConnectionAborted,
+            format!("WebSocket closed by peer with {}", code),
+        )),
+    }
 }
 
 fn map_ws_error(e: tungstenite::Error) -> io::Error {
-    io::Error::new(io::ErrorKind::BrokenPipe, format!("Tungstenite error: {}", e))
+    match e {
+        tungstenite::Error::Io(e) => e,
+        tungstenite::Error::Protocol(ProtocolError::ResetWithoutClosingHandshake) =>
+            io::Error::new(io::ErrorKind::ConnectionReset, "WebSocket closed without close frame"),
+        e => io::Error::new(io::ErrorKind::BrokenPipe, format!("Tungstenite error: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+    use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+    use tokio_tungstenite::tungstenite::protocol::Role;
+
+    use super::*;
+
+    async fn ws_pair() -> (WsIo<DuplexStream>, WebSocketStream<DuplexStream>) {
+        let (server, client) = duplex(64 * 1024);
+        let server = WebSocketStream::from_raw_socket(server, Role::Server, None).await;
+        let client = WebSocketStream::from_raw_socket(client, Role::Client, None).await;
+        (WsIo::new(server), client)
+    }
+
+    async fn close(client: &mut WebSocketStream<DuplexStream>, code: CloseCode) {
+        let frame = CloseFrame { code, reason: Default::default() };
+        client.send(Message::Close(Some(frame))).await.unwrap();
+    }
+
+    #[test]
+    fn test_policy() {
+        let headers = |pairs: &[(&str, &'static str)]| -> HeaderMap {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+                .collect()
+        };
+
+        let policy = WebSocketPolicy {
+            allowed_origins: Some(vec!["https://wallet.example/".to_string()]),
+            ..WebSocketPolicy::default()
+        };
+        assert!(policy.check_origin(&headers(&[("origin", "https://WALLET.example")])).is_ok());
+        assert!(policy.check_origin(&headers(&[])).is_ok(), "non-browser clients send no origin");
+        for origin in ["https://evil.example", "http://wallet.example", "null"] {
+            let origin = headers(&[("origin", origin)]);
+            assert!(matches!(policy.check_origin(&origin), Err(Error::OriginNotAllowed)));
+        }
+        let any = headers(&[("origin", "https://evil.example")]);
+        assert!(WebSocketPolicy::default().check_origin(&any).is_ok());
+
+        let any_origin = WebSocketPolicy::default();
+        assert_eq!(any_origin.negotiate_subprotocol(&headers(&[])).unwrap(), None);
+        let offered = headers(&[("sec-websocket-protocol", "chat, ohttp-bootstrap.tls")]);
+        assert_eq!(any_origin.negotiate_subprotocol(&offered).unwrap().unwrap(), SUBPROTOCOL);
+        assert_eq!(policy.negotiate_subprotocol(&offered).unwrap().unwrap(), SUBPROTOCOL);
+        let offered = headers(&[("sec-websocket-protocol", "chat")]);
+        assert!(matches!(any_origin.negotiate_subprotocol(&offered), Err(Error::BadRequest(_))));
+        assert!(
+            matches!(policy.negotiate_subprotocol(&headers(&[])), Err(Error::BadRequest(_))),
+            "the subprotocol is required when origins are restricted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_and_ping_interleaved() {
+        let (mut ws_io, mut client) = ws_pair().await;
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            ws_io.read_to_end(&mut received).await.map(|_| received)
+        });
+
+        let binary = OpCode::Data(Data::Binary);
+        let continuation = OpCode::Data(Data::Continue);
+        client.send(Message::Frame(Frame::message("hello", binary, false))).await.unwrap();
+        client.send(Message::Ping("are you there".into())).await.unwrap();
+        client.send(Message::Frame(Frame::message(" world", continuation, true))).await.unwrap();
+        client.send(Message::Binary("!".into())).await.unwrap();
+        match client.next().await {
+            Some(Ok(Message::Pong(data))) => assert_eq!(data, "are you there"),
+            other => panic!("expected pong, got {:?}", other),
+        }
+        close(&mut client, CloseCode::Normal).await;
+
+        let received = reader.await.unwrap().expect("normal close should read as EOF");
+        assert_eq!(received, b"hello world!", "fragments should be reassembled");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_text() {
+        let (mut ws_io, mut client) = ws_pair().await;
+        client.send(Message::Text("hello".into())).await.unwrap();
+        let err = ws_io.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_closed_by_peer() {
+        let (mut ws_io, mut client) = ws_pair().await;
+        close(&mut client, CloseCode::Away).await;
+        assert_eq!(ws_io.read(&mut [0; 16]).await.unwrap(), 0, "going away should read as EOF");
+
+        let (mut ws_io, mut client) = ws_pair().await;
+        close(&mut client, CloseCode::Policy).await;
+        let err = ws_io.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+
+        let (mut ws_io, mut client) = ws_pair().await;
+        client.send(Message::Binary("partial".into())).await.unwrap();
+        drop(client);
+        let mut received = [0; 7];
+        ws_io.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"partial", "data before an abrupt close should be read");
+        let err = ws_io.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset, "abrupt close is not EOF");
+    }
+
+    #[tokio::test]
+    async fn test_close_codes() {
+        let (mut ws_io, mut client) = ws_pair().await;
+        ws_io.write_all(b"keys").await.unwrap();
+        ws_io.shutdown().await.unwrap();
+        assert!(matches!(client.next().await, Some(Ok(Message::Binary(data))) if data == "keys"));
+        match client.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Normal),
+            other => panic!("expected close frame, got {:?}", other),
+        }
+
+        let (mut ws_io, mut client) = ws_pair().await;
+        ws_io.close(CloseCode::Policy).await.unwrap();
+        match client.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+            other => panic!("expected close frame, got {:?}", other),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pings() {
+        const INTERVAL: Duration = Duration::from_secs(15);
+
+        let (ws_io, mut client) = ws_pair().await;
+        let mut ws_io = ws_io.with_ping_interval(INTERVAL);
+        let responder = tokio::spawn(async move {
+            let mut pings = 0;
+            while let Some(Ok(message)) = client.next().await {
+                if message.is_ping() {
+                    pings += 1;
+                }
+            }
+            pings
+        });
+        let read = tokio::time::timeout(10 * INTERVAL, ws_io.read(&mut [0; 16])).await;
+        assert!(read.is_err(), "a responsive peer should not time out");
+        drop(ws_io);
+        assert!(responder.await.unwrap() >= 9, "the peer should have been pinged");
+
+        let (ws_io, _unresponsive) = ws_pair().await;
+        let mut ws_io = ws_io.with_ping_interval(INTERVAL);
+        let start = Instant::now();
+        let err = ws_io.read(&mut [0; 16]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), 2 * INTERVAL, "dead peers are detected by the next ping");
+    }
 }
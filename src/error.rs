@@ -3,7 +3,7 @@ use std::time::Duration;
 use http_body_util::combinators::BoxBody;
 use http_body_util::BodyExt;
 use hyper::body::Bytes;
-use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::header::{HeaderValue, CACHE_CONTROL, RETRY_AFTER};
 use hyper::{Response, StatusCode};
 use tracing::error;
 
@@ -15,12 +15,23 @@ pub(crate) type BoxError = Box<dyn std::error::Error + Send + Sync>;
 #[allow(clippy::enum_variant_names)]
 pub(crate) enum Error {
     BadGateway,
+    GatewayTimeout,
     MethodNotAllowed,
     UnsupportedMediaType,
+    PayloadTooLarge,
     BadRequest(String),
+    /// The gateway opted out of relaying, until the duration elapses.
+    Forbidden(Duration),
+    /// The gateway is on the operator's denylist.
+    Denied,
+    /// The browser page's origin may not open bootstrap tunnels.
+    OriginNotAllowed,
     NotFound,
     InternalServerError(BoxError),
     Unavailable(Duration),
+    /// The gateway could not be probed, until the duration elapses.
+    GatewayUnavailable(Duration),
+    TooManyRequests(Duration),
 }
 
 impl Error {
@@ -28,12 +39,31 @@ impl Error {
         let mut res = Response::new(empty());
         match self {
             Self::UnsupportedMediaType => *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::PayloadTooLarge => *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE,
             Self::BadGateway => *res.status_mut() = StatusCode::BAD_GATEWAY,
+            Self::GatewayTimeout => *res.status_mut() = StatusCode::GATEWAY_TIMEOUT,
             Self::MethodNotAllowed => *res.status_mut() = StatusCode::METHOD_NOT_ALLOWED,
             Self::BadRequest(e) => {
                 *res.status_mut() = StatusCode::BAD_REQUEST;
                 *res.body_mut() = full(e.to_string()).boxed();
             }
+            Self::Forbidden(max_age) => {
+                *res.status_mut() = StatusCode::FORBIDDEN;
+                res.headers_mut().append(
+                    CACHE_CONTROL,
+                    header_value(format!("max-age={}", ceil_secs(*max_age))),
+                );
+            }
+            Self::Denied => {
+                *res.status_mut() = StatusCode::FORBIDDEN;
+                // the denylist may be reloaded at any time
+                res.headers_mut().append(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+                *res.body_mut() = full("Gateway denied by relay policy").boxed();
+            }
+            Self::OriginNotAllowed => {
+                *res.status_mut() = StatusCode::FORBIDDEN;
+                *res.body_mut() = full("Origin not allowed").boxed();
+            }
             Self::NotFound => *res.status_mut() = StatusCode::NOT_FOUND,
             Self::InternalServerError(internal_error) => {
                 error!("Internal server error: {}", internal_error);
@@ -47,6 +77,18 @@ impl Error {
                         .expect("header value should always be valid"),
                 );
             }
+            Self::GatewayUnavailable(max_age) => {
+                *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                let secs = ceil_secs(*max_age);
+                res.headers_mut().append(RETRY_AFTER, header_value(secs.to_string()));
+                res.headers_mut().append(CACHE_CONTROL, header_value(format!("max-age={}", secs)));
+            }
+            Self::TooManyRequests(retry_after) => {
+                *res.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+                // round up so clients never retry before a token is available
+                res.headers_mut()
+                    .append(RETRY_AFTER, header_value(ceil_secs(*retry_after).to_string()));
+            }
         };
         res
     }
@@ -56,14 +98,42 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Self::UnsupportedMediaType => write!(f, "Unsupported media type"),
+            Self::PayloadTooLarge => write!(f, "Payload too large"),
             Self::BadGateway => write!(f, "Bad gateway"),
+            Self::GatewayTimeout => write!(f, "Gateway timeout"),
             Self::MethodNotAllowed => write!(f, "Method not allowed"),
             Self::BadRequest(e) => write!(f, "Bad request: {}", e),
+            Self::Forbidden(_) => write!(f, "Forbidden"),
+            Self::Denied => write!(f, "Gateway denied"),
+            Self::OriginNotAllowed => write!(f, "Origin not allowed"),
             Self::NotFound => write!(f, "Not found"),
             Self::InternalServerError(e) => write!(f, "Internal server error: {}", e),
             Self::Unavailable(_) => write!(f, "Service unavailable"),
+            Self::GatewayUnavailable(_) => write!(f, "Gateway unavailable"),
+            Self::TooManyRequests(_) => write!(f, "Too many requests"),
         }
     }
 }
 
 impl std::error::Error for Error {}
+
+/// Whole seconds in `duration`, rounded up.
+pub(crate) fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs().saturating_add(u64::from(duration.subsec_nanos() > 0))
+}
+
+fn header_value(value: String) -> HeaderValue {
+    HeaderValue::from_str(&value).expect("header value should always be valid")
+}
+
+/// The kind of the first IO error in an error's chain of sources, if any.
+pub(crate) fn io_error_kind(err: &(dyn std::error::Error + 'static)) -> Option<std::io::ErrorKind> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+            return Some(io_error.kind());
+        }
+        source = err.source();
+    }
+    None
+}
@@ -0,0 +1,268 @@
+//! Gateways which the operator allows or denies regardless of their opt-in.
+//!
+//! Lists are given as patterns, each one of
+//!
+//! - an exact host, optionally with a port, e.g. `payjo.in` or `payjo.in:443`
+//! - a domain suffix matching any subdomain, e.g. `*.payjo.in`
+//! - a CIDR range matching IP address hosts, e.g. `10.0.0.0/8` or `fc00::/7`
+//!
+//! Denied CIDR ranges also refuse connections to the addresses gateway names
+//! resolve to, except through upstream proxies which resolve names
+//! themselves, since the relay never sees those addresses.
+//!
+//! A list file has one `allow <pattern>` or `deny <pattern>` per line. Empty
+//! lines and lines starting with `#` are ignored.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use tracing::info;
+
+use crate::error::BoxError;
+use crate::gateway_uri::GatewayUri;
+
+/// Gateways which are relayed to without probing them for opt-in, and
+/// gateways which are never probed or relayed to. Denied gateways take
+/// precedence over allowed ones, including the default gateway.
+///
+/// Clones share the same lists, so lists replaced through [`Self::reload`]
+/// or [`Self::replace`] take effect in a running relay.
+#[derive(Debug, Clone, Default)]
+pub struct GatewayLists {
+    path: Option<PathBuf>,
+    lists: Arc<RwLock<Lists>>,
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+    allow: Vec<GatewayPattern>,
+    deny: Vec<GatewayPattern>,
+}
+
+/// Whether a gateway is on one of the [`GatewayLists`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Listed {
+    Allowed,
+    Denied,
+}
+
+impl GatewayLists {
+    /// Lists of allowed and denied gateway patterns. Fails if any pattern is
+    /// invalid.
+    pub fn new<'a>(
+        allow: impl IntoIterator<Item = &'a str>,
+        deny: impl IntoIterator<Item = &'a str>,
+    ) -> Result<Self, BoxError> {
+        let lists = Self::default();
+        lists.replace(allow, deny)?;
+        Ok(lists)
+    }
+
+    /// Load lists from a file, see the [module documentation](self) for the
+    /// format.
+    pub fn from_file(path: impl Into<PathBuf>) -> Result<Self, BoxError> {
+        let path = path.into();
+        let lists = Self { lists: Arc::new(RwLock::new(load_lists(&path)?)), path: Some(path) };
+        Ok(lists)
+    }
+
+    /// Reload the lists from the file they were loaded from, e.g. on SIGHUP.
+    /// On error the previously loaded lists remain in use.
+    pub fn reload(&self) -> Result<(), BoxError> {
+        let path = self.path.as_ref().ok_or("gateway lists were not loaded from a file")?;
+        *self.lists.write().expect("lock should not be poisoned") = load_lists(path)?;
+        info!("Reloaded gateway lists from {}", path.display());
+        Ok(())
+    }
+
+    /// Replace the lists. On error the previous lists remain in use.
+    pub fn replace<'a>(
+        &self,
+        allow: impl IntoIterator<Item = &'a str>,
+        deny: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), BoxError> {
+        let lists = Lists {
+            allow: allow.into_iter().map(GatewayPattern::from_str).collect::<Result<_, _>>()?,
+            deny: deny.into_iter().map(GatewayPattern::from_str).collect::<Result<_, _>>()?,
+        };
+        *self.lists.write().expect("lock should not be poisoned") = lists;
+        Ok(())
+    }
+
+    pub(crate) fn lookup(&self, gateway: &GatewayUri) -> Option<Listed> {
+        let lists = self.lists.read().expect("lock should not be poisoned");
+        if lists.deny.iter().any(|pattern| pattern.matches(gateway)) {
+            Some(Listed::Denied)
+        } else if lists.allow.iter().any(|pattern| pattern.matches(gateway)) {
+            Some(Listed::Allowed)
+        } else {
+            None
+        }
+    }
+
+    /// Whether `ip` is in a denied CIDR range.
+    pub(crate) fn denies_ip(&self, ip: IpAddr) -> bool {
+        let lists = self.lists.read().expect("lock should not be poisoned");
+        lists.deny.iter().any(|pattern| match pattern {
+            GatewayPattern::Cidr(network, prefix_len) => in_network(ip, *network, *prefix_len),
+            _ => false,
+        })
+    }
+}
+
+fn load_lists(path: &Path) -> Result<Lists, BoxError> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut lists = Lists::default();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |e| format!("{}:{}: {}", path.display(), number + 1, e);
+        let (list, pattern) = match line.split_once(char::is_whitespace) {
+            Some(("allow", pattern)) => (&mut lists.allow, pattern),
+            Some(("deny", pattern)) => (&mut lists.deny, pattern),
+            _ =>
+                return Err(invalid("expected `allow <pattern>` or `deny <pattern>`".into()).into()),
+        };
+        list.push(GatewayPattern::from_str(pattern.trim()).map_err(|e| invalid(e.to_string()))?);
+    }
+    Ok(lists)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum GatewayPattern {
+    Authority { host: String, port: Option<u16> },
+    DomainSuffix(String),
+    Cidr(IpAddr, u8),
+}
+
+impl GatewayPattern {
+    fn matches(&self, gateway: &GatewayUri) -> bool {
+        let authority = gateway.authority();
+        self.matches_host(authority.host(), authority.port_u16())
+    }
+
+    /// Whether the pattern matches `host`, which may be a bracketed IPv6
+    /// address, on `port`.
+    pub(crate) fn matches_host(&self, host: &str, port: Option<u16>) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        match self {
+            Self::Authority { host: pattern, port: pattern_port } =>
+                host.eq_ignore_ascii_case(pattern)
+                    && pattern_port.is_none_or(|pattern_port| port == Some(pattern_port)),
+            Self::DomainSuffix(suffix) => host
+                .len()
+                .checked_sub(suffix.len())
+                .filter(|&start| start > 0 && host.as_bytes()[start - 1] == b'.')
+                .is_some_and(|start| host[start..].eq_ignore_ascii_case(suffix)),
+            Self::Cidr(network, prefix_len) =>
+                host.parse::<IpAddr>().is_ok_and(|ip| in_network(ip, *network, *prefix_len)),
+        }
+    }
+}
+
+impl FromStr for GatewayPattern {
+    type Err = BoxError;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if let Some(suffix) = pattern.strip_prefix("*.") {
+            if suffix.is_empty() || suffix.contains(['*', ':', '/']) {
+                return Err(format!("invalid domain suffix {}", pattern).into());
+            }
+            return Ok(Self::DomainSuffix(suffix.to_ascii_lowercase()));
+        }
+
+        if let Some((network, prefix_len)) = pattern.split_once('/') {
+            let network: IpAddr =
+                network.parse().map_err(|_| format!("invalid CIDR network {}", pattern))?;
+            let max_len = if network.is_ipv4() { 32 } else { 128 };
+            let prefix_len = prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|&len| len <= max_len)
+                .ok_or_else(|| format!("invalid CIDR prefix length {}", pattern))?;
+            return Ok(Self::Cidr(network, prefix_len));
+        }
+
+        let authority = http::uri::Authority::from_str(pattern)
+            .map_err(|e| format!("invalid gateway authority {}: {}", pattern, e))?;
+        if authority.as_str().contains('@') {
+            return Err(format!("gateway authority {} must not contain userinfo", pattern).into());
+        }
+        let host = authority.host().trim_start_matches('[').trim_end_matches(']');
+        Ok(Self::Authority { host: host.to_ascii_lowercase(), port: authority.port_u16() })
+    }
+}
+
+/// Whether `ip` is within the `prefix_len` bit prefix of `network`.
+fn in_network(ip: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX.checked_shl(128 - u32::from(prefix_len)).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn test_patterns() {
+        let matches = |pattern: &str, gateway: &str| {
+            GatewayPattern::from_str(pattern)
+                .unwrap()
+                .matches(&GatewayUri::from_str(gateway).unwrap())
+        };
+        assert!(matches("payjo.in", "https://payjo.in"));
+        assert!(matches("PAYJO.in", "http://payjo.in:8080"), "hosts are case insensitive");
+        assert!(matches("payjo.in:443", "https://payjo.in"), "default port should match");
+        assert!(!matches("payjo.in:443", "http://payjo.in"));
+        assert!(!matches("payjo.in", "https://mailbox.payjo.in"));
+        assert!(matches("*.payjo.in", "https://mailbox.payjo.in"));
+        assert!(!matches("*.payjo.in", "https://payjo.in"), "suffix only matches subdomains");
+        assert!(!matches("*.payjo.in", "https://notpayjo.in"), "suffix ends on a label");
+        assert!(matches("10.0.0.0/8", "http://10.1.2.3"));
+        assert!(!matches("10.0.0.0/8", "http://11.1.2.3"));
+        assert!(matches("0.0.0.0/0", "http://192.0.2.1"));
+        assert!(matches("fc00::/7", "http://[fd00::1]:8080"));
+        assert!(matches("[::1]", "http://[::1]"));
+        assert!(!matches("10.0.0.0/8", "http://10.example"), "CIDRs only match IP addresses");
+
+        for invalid in ["*.", "*.*.payjo.in", "10.0.0.0/33", "payjo.in/8", "user@payjo.in", ""] {
+            assert!(GatewayPattern::from_str(invalid).is_err(), "{} should be invalid", invalid);
+        }
+    }
+
+    #[test]
+    fn test_lists() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "# mailboxes\nallow *.payjo.in\n\ndeny evil.payjo.in").unwrap();
+        let lists = GatewayLists::from_file(file.path()).expect("lists should be valid");
+        let lookup = |gateway| lists.lookup(&GatewayUri::from_static(gateway));
+        assert_eq!(lookup("https://mailbox.payjo.in"), Some(Listed::Allowed));
+        assert_eq!(lookup("https://evil.payjo.in"), Some(Listed::Denied), "deny takes precedence");
+        assert_eq!(lookup("https://example.com"), None);
+
+        let clone = lists.clone();
+        writeln!(file, "allow example.com\nbogus example.com").unwrap();
+        assert!(clone.reload().is_err(), "invalid lists should be rejected");
+        assert_eq!(lookup("https://mailbox.payjo.in"), Some(Listed::Allowed));
+        std::fs::write(file.path(), "deny *.payjo.in\nallow example.com\n").unwrap();
+        clone.reload().expect("lists should be valid");
+        assert_eq!(lookup("https://mailbox.payjo.in"), Some(Listed::Denied), "clones share lists");
+        assert_eq!(lookup("https://example.com"), Some(Listed::Allowed));
+    }
+}
@@ -0,0 +1,452 @@
+//! Spread requests to the default gateway over a pool of gateways which share
+//! its OHTTP key configuration, so that relaying continues while some of them
+//! are down.
+//!
+//! Members are ejected from the pool after consecutive failures, whether
+//! relaying to them or checking their health, and re-admitted once their
+//! ejection time has passed or a health check succeeds. Health checks fetch
+//! each member's keys, and members are left out of the pool until their keys
+//! are known to match the default gateway's, since clients encapsulate
+//! requests to the default gateway's keys.
+
+use std::str::FromStr;
+use std::sync::{Mutex, Weak};
+use std::time::Duration;
+
+use hyper::body::Bytes;
+use tokio::time::{Instant, MissedTickBehavior};
+use tracing::{info, warn};
+
+use crate::error::BoxError;
+use crate::gateway_uri::GatewayUri;
+use crate::key_cache::fetch_keys;
+use crate::timeouts::UpstreamTimeouts;
+use crate::HttpClient;
+
+/// How requests are spread over the healthy members of a gateway pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balancing {
+    /// Each member in turn, as often as its weight relative to the others.
+    #[default]
+    WeightedRoundRobin,
+    /// The member with the fewest requests in flight relative to its weight.
+    LeastOutstanding,
+}
+
+impl FromStr for Balancing {
+    type Err = BoxError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Self::WeightedRoundRobin),
+            "least_outstanding" => Ok(Self::LeastOutstanding),
+            _ => Err(format!("unknown balancing {}, expected round_robin or least_outstanding", s)
+                .into()),
+        }
+    }
+}
+
+/// Gateways pooled with the default gateway, see the
+/// [module documentation](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GatewayPoolConfig {
+    /// Gateways pooled with the default gateway and their weights. The
+    /// default gateway has weight 1 unless it is listed. Weights of 0 count
+    /// as 1.
+    pub members: Vec<(GatewayUri, u32)>,
+    pub balancing: Balancing,
+    /// Consecutive failures after which a member is ejected.
+    pub max_failures: u32,
+    /// How long a member is first ejected for, doubled with every ejection
+    /// until it succeeds again.
+    pub ejection_time: Duration,
+    /// The longest a member is ejected for.
+    pub max_ejection_time: Duration,
+    /// How often the members' keys are fetched to check their health.
+    pub health_check_interval: Duration,
+}
+
+impl Default for GatewayPoolConfig {
+    fn default() -> Self {
+        Self {
+            members: Vec::new(),
+            balancing: Balancing::default(),
+            max_failures: 3,
+            ejection_time: Duration::from_secs(10),
+            max_ejection_time: Duration::from_secs(5 * 60),
+            health_check_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct GatewayPool {
+    /// The default gateway first, then the other members in the configured
+    /// order.
+    members: Vec<(GatewayUri, u32)>,
+    config: GatewayPoolConfig,
+    states: Mutex<Vec<MemberState>>,
+    client: HttpClient,
+    timeouts: UpstreamTimeouts,
+}
+
+#[derive(Debug, Default)]
+struct MemberState {
+    /// The keys the member served at its last successful health check.
+    keys: Option<Bytes>,
+    /// Whether the member's keys are known to match the default gateway's.
+    keys_match: bool,
+    consecutive_failures: u32,
+    /// Ejections since the member last succeeded.
+    ejections: u32,
+    ejected_until: Option<Instant>,
+    /// Smooth weighted round robin state, see [`GatewayPool::select`].
+    current_weight: i64,
+    outstanding: usize,
+}
+
+impl GatewayPool {
+    pub(crate) fn new(
+        default_gateway: &GatewayUri,
+        mut config: GatewayPoolConfig,
+        client: HttpClient,
+        timeouts: UpstreamTimeouts,
+    ) -> Self {
+        let mut members = vec![(default_gateway.clone(), 1)];
+        for (gateway, weight) in std::mem::take(&mut config.members) {
+            match members.iter_mut().find(|(member, _)| *member == gateway) {
+                Some(member) => member.1 = weight.max(1),
+                None => members.push((gateway, weight.max(1))),
+            }
+        }
+        let mut states: Vec<_> = members.iter().map(|_| MemberState::default()).collect();
+        // relay to the default gateway until the others' keys are checked
+        states[0].keys_match = true;
+        Self { members, config, states: Mutex::new(states), client, timeouts }
+    }
+
+    /// The indices of members for which `excluded` is true, for leaving them
+    /// out of [`Self::select`] like tried ones.
+    pub(crate) fn members_where(&self, excluded: impl Fn(&GatewayUri) -> bool) -> Vec<usize> {
+        (0..self.members.len()).filter(|&index| excluded(&self.members[index].0)).collect()
+    }
+
+    /// Pick a member to relay to, other than those `tried` already. Falls
+    /// back to the default gateway if no member is admitted, so that the
+    /// relay behaves as it would without a pool.
+    pub(crate) fn select(&self, tried: &[usize]) -> Option<Lease<'_>> {
+        let mut states = self.states.lock().expect("lock should not be poisoned");
+        let now = Instant::now();
+        let mut candidates: Vec<usize> = (0..self.members.len())
+            .filter(|index| !tried.contains(index))
+            .filter(|&index| {
+                let state = &states[index];
+                state.keys_match && state.ejected_until.is_none_or(|until| until <= now)
+            })
+            .collect();
+        if candidates.is_empty() && !tried.contains(&0) {
+            candidates.push(0);
+        }
+
+        let weight = |index: usize| i64::from(self.members[index].1);
+        let index = match self.config.balancing {
+            // nginx's smooth weighted round robin, which interleaves members
+            // rather than sending a member's whole weight in a row
+            Balancing::WeightedRoundRobin => {
+                let total: i64 = candidates.iter().map(|&index| weight(index)).sum();
+                for &index in &candidates {
+                    states[index].current_weight += weight(index);
+                }
+                let selected = candidates.iter().copied().max_by_key(|&index| {
+                    (states[index].current_weight, std::cmp::Reverse(index))
+                })?;
+                states[selected].current_weight -= total;
+                selected
+            }
+            // compare outstanding / weight without dividing
+            Balancing::LeastOutstanding => candidates.iter().copied().min_by(|&a, &b| {
+                let load = |index: usize, other: usize| {
+                    (states[index].outstanding as i64 + 1) * weight(other)
+                };
+                load(a, b).cmp(&load(b, a))
+            })?,
+        };
+        states[index].outstanding += 1;
+        Some(Lease { pool: self, index })
+    }
+
+    fn record_success(&self, index: usize) {
+        let mut states = self.states.lock().expect("lock should not be poisoned");
+        let state = &mut states[index];
+        if state.ejected_until.take().is_some_and(|until| until > Instant::now()) {
+            info!("Re-admitting {} to the gateway pool", self.members[index].0.to_uri());
+        }
+        state.consecutive_failures = 0;
+        state.ejections = 0;
+    }
+
+    fn record_failure(&self, index: usize) {
+        let mut states = self.states.lock().expect("lock should not be poisoned");
+        let state = &mut states[index];
+        let now = Instant::now();
+        if state.ejected_until.is_some_and(|until| until > now) {
+            return;
+        }
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.config.max_failures {
+            let ejection = self
+                .config
+                .ejection_time
+                .saturating_mul(2u32.saturating_pow(state.ejections))
+                .min(self.config.max_ejection_time);
+            warn!(
+                "Ejecting {} from the gateway pool for {:?}",
+                self.members[index].0.to_uri(),
+                ejection
+            );
+            state.ejected_until = Some(now + ejection);
+            state.ejections = state.ejections.saturating_add(1);
+            state.consecutive_failures = 0;
+        }
+    }
+
+    /// Fetch every member's keys, recording whether they answered and
+    /// whether their keys match the default gateway's.
+    async fn check_health(&self) {
+        for (index, (gateway, _)) in self.members.iter().enumerate() {
+            match fetch_keys(&self.client, &self.timeouts, gateway).await {
+                Ok((_, keys)) => {
+                    self.record_keys(index, keys);
+                    self.record_success(index);
+                }
+                Err(e) => {
+                    warn!("Health check of pooled gateway {} failed: {}", gateway.to_uri(), e);
+                    self.record_failure(index);
+                }
+            }
+        }
+    }
+
+    /// Record the keys a member served. Other members are only pooled once
+    /// the default gateway's keys are known and they serve the same, and a
+    /// change of the default gateway's keys applies to all of them at once.
+    fn record_keys(&self, index: usize, keys: Bytes) {
+        let mut states = self.states.lock().expect("lock should not be poisoned");
+        if index == 0 && states[0].keys.as_ref().is_some_and(|previous| *previous != keys) {
+            info!("The default gateway's key configuration changed");
+        }
+        states[index].keys = Some(keys);
+        let reevaluated = match index {
+            0 => 1..self.members.len(),
+            _ => index..index + 1,
+        };
+        let default_keys = states[0].keys.clone();
+        for index in reevaluated {
+            let state = &mut states[index];
+            let keys_match = default_keys.is_some() && state.keys == default_keys;
+            if state.keys_match != keys_match {
+                let gateway = self.members[index].0.to_uri();
+                match keys_match {
+                    true => info!("Pooling {}, which serves the default gateway's keys", gateway),
+                    false => warn!("Not pooling {}, which serves other keys", gateway),
+                }
+            }
+            state.keys_match = keys_match;
+        }
+    }
+
+    /// Check the members' health every health check interval for as long as
+    /// the pool is in use.
+    pub(crate) async fn check_health_until_dropped(pool: Weak<Self>) {
+        let Some(interval) = pool.upgrade().map(|pool| pool.config.health_check_interval) else {
+            return;
+        };
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            match pool.upgrade() {
+                Some(pool) => pool.check_health().await,
+                None => return,
+            }
+        }
+    }
+}
+
+/// A member selected for a request, which counts as outstanding until the
+/// lease is dropped.
+#[derive(Debug)]
+pub(crate) struct Lease<'a> {
+    pool: &'a GatewayPool,
+    index: usize,
+}
+
+impl Lease<'_> {
+    pub(crate) fn gateway(&self) -> &GatewayUri { &self.pool.members[self.index].0 }
+
+    pub(crate) fn index(&self) -> usize { self.index }
+
+    pub(crate) fn succeeded(self) { self.pool.record_success(self.index) }
+
+    pub(crate) fn failed(self) { self.pool.record_failure(self.index) }
+}
+
+impl Drop for Lease<'_> {
+    fn drop(&mut self) {
+        let mut states = self.pool.states.lock().expect("lock should not be poisoned");
+        states[self.index].outstanding -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(members: &[(&'static str, u32)], balancing: Balancing) -> GatewayPool {
+        let config = GatewayPoolConfig {
+            members: members
+                .iter()
+                .map(|&(gateway, weight)| (GatewayUri::from_static(gateway), weight))
+                .collect(),
+            balancing,
+            ..GatewayPoolConfig::default()
+        };
+        let pool = GatewayPool::new(
+            &GatewayUri::from_static("https://a.example"),
+            config,
+            HttpClient::default(),
+            UpstreamTimeouts::PROBE,
+        );
+        for state in pool.states.lock().unwrap().iter_mut() {
+            state.keys_match = true;
+        }
+        pool
+    }
+
+    fn selections(pool: &GatewayPool, count: usize) -> String {
+        (0..count)
+            .map(|_| {
+                let lease = pool.select(&[]).unwrap();
+                let host = lease.gateway().authority().host().to_string();
+                host[..1].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let pool = pool(
+            &[("https://b.example", 2), ("https://c.example", 0)],
+            Balancing::WeightedRoundRobin,
+        );
+        assert_eq!(pool.members.len(), 3);
+        assert_eq!(selections(&pool, 8), "bacbbacb", "members should be interleaved by weight");
+    }
+
+    #[test]
+    fn test_least_outstanding() {
+        let pool = pool(&[("https://b.example", 2)], Balancing::LeastOutstanding);
+        let first = pool.select(&[]).unwrap();
+        let second = pool.select(&[]).unwrap();
+        let third = pool.select(&[]).unwrap();
+        assert_eq!((first.index(), second.index(), third.index()), (1, 0, 1));
+        drop(first);
+        assert_eq!(pool.select(&[]).unwrap().index(), 1, "heavier members take more requests");
+        drop((second, third));
+        assert_eq!(pool.select(&[1]).unwrap().index(), 0, "tried members should be skipped");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_ejection() {
+        let pool = pool(&[("https://b.example", 1)], Balancing::WeightedRoundRobin);
+        for _ in 0..3 {
+            pool.select(&[1]).unwrap().failed();
+        }
+        assert_eq!(selections(&pool, 3), "bbb", "the failing member should be ejected");
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(selections(&pool, 2), "ab", "members are re-admitted after the ejection");
+        for _ in 0..3 {
+            pool.select(&[1]).unwrap().failed();
+        }
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(selections(&pool, 2), "bb", "repeated ejections should last longer");
+        pool.record_success(0);
+        assert_eq!(selections(&pool, 2), "ab", "successful health checks re-admit early");
+
+        pool.states.lock().unwrap()[1].ejected_until =
+            Some(Instant::now() + Duration::from_secs(1));
+        pool.states.lock().unwrap()[0].ejected_until =
+            Some(Instant::now() + Duration::from_secs(1));
+        assert_eq!(selections(&pool, 1), "a", "the default gateway is the last resort");
+        assert!(pool.select(&[0]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_check_compares_keys() {
+        let mut servers = Vec::new();
+        for keys in [b"keys", b"keys", b"rekd"] {
+            let mut server = mockito::Server::new_async().await;
+            server
+                .mock("GET", "/.well-known/ohttp-gateway")
+                .with_header("content-type", "application/ohttp-keys")
+                .with_body(keys)
+                .create_async()
+                .await;
+            servers.push(server);
+        }
+        let unreachable = GatewayUri::from_static("http://127.0.0.1:1");
+        let config = GatewayPoolConfig {
+            members: servers[1..]
+                .iter()
+                .map(|server| (GatewayUri::from_str(&server.url()).unwrap(), 1))
+                .chain([(unreachable, 1)])
+                .collect(),
+            max_failures: 1,
+            ..GatewayPoolConfig::default()
+        };
+        let default_gateway = GatewayUri::from_str(&servers[0].url()).unwrap();
+        let pool = GatewayPool::new(
+            &default_gateway,
+            config,
+            HttpClient::default(),
+            UpstreamTimeouts::PROBE,
+        );
+        assert_eq!(selections(&pool, 2), "11", "only the default gateway is pooled at first");
+
+        pool.check_health().await;
+        let states = pool.states.lock().unwrap();
+        assert!(states[1].keys_match, "members with the same keys should be pooled");
+        assert!(!states[2].keys_match, "members with other keys should not be pooled");
+        assert!(states[3].ejected_until.is_some(), "unreachable members should be ejected");
+    }
+
+    #[test]
+    fn test_keys_compared_to_default_gateway() {
+        let pool = GatewayPool::new(
+            &GatewayUri::from_static("https://a.example"),
+            GatewayPoolConfig {
+                members: vec![
+                    (GatewayUri::from_static("https://b.example"), 1),
+                    (GatewayUri::from_static("https://c.example"), 1),
+                ],
+                ..GatewayPoolConfig::default()
+            },
+            HttpClient::default(),
+            UpstreamTimeouts::PROBE,
+        );
+        let pooled = || -> Vec<bool> {
+            pool.states.lock().unwrap().iter().map(|state| state.keys_match).collect()
+        };
+        pool.record_keys(1, Bytes::from_static(b"keys"));
+        pool.record_keys(2, Bytes::from_static(b"keys"));
+        assert_eq!(pooled(), [true, false, false], "the default gateway's keys are not known");
+
+        pool.record_keys(0, Bytes::from_static(b"keys"));
+        assert_eq!(pooled(), [true, true, true]);
+        pool.record_keys(2, Bytes::from_static(b"rekd"));
+        assert_eq!(pooled(), [true, true, false]);
+        pool.record_keys(0, Bytes::from_static(b"rekd"));
+        assert_eq!(pooled(), [true, false, true], "members should follow the default's keys");
+    }
+}
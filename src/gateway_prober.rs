@@ -1,38 +1,89 @@
 use std::cmp::{Ordering, Reverse};
 use std::collections::{BinaryHeap, HashMap};
-use std::error::Error;
 use std::io::{ErrorKind, Read};
-use std::time::Duration;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::BytesMut;
 use futures::future::{self, FutureExt};
 use http_body_util::BodyExt;
 use hyper::body::Incoming;
+use hyper::header::{CACHE_CONTROL, RETRY_AFTER};
+use hyper::{HeaderMap, StatusCode};
 use tokio::sync::{oneshot, RwLock};
 use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, warn};
 
+use crate::error::{io_error_kind, BoxError};
 use crate::gateway_uri::GatewayUri;
+use crate::metrics::{self, Metrics};
+use crate::timeouts::UpstreamTimeouts;
 
 // these are only pub for the integration test
 pub const MAGIC_BIP77_PURPOSE: &[u8] = b"BIP77 454403bb-9f7b-4385-b31f-acd2dae20b7e";
 pub const ALLOWED_PURPOSES_CONTENT_TYPE: &str = "application/x-ohttp-allowed-purposes";
-const DEFAULT_CAPACITY: usize = 1000;
+pub(crate) const DEFAULT_CAPACITY: usize = 1000;
+
+/// First line of a prober snapshot file, to be bumped whenever the format
+/// changes.
+const SNAPSHOT_VERSION: &str = "ohttp-relay prober snapshot v1";
+/// How often the prober's known gateways are written to the snapshot file.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What probing a gateway revealed about its opt-in.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub(crate) enum Judgement {
+    /// The gateway explicitly allows BIP 77.
+    OptIn,
+    /// The gateway answered, but does not allow BIP 77, e.g. with a 4xx or
+    /// without the BIP 77 purpose.
+    OptOut,
+    /// The gateway could not be asked, e.g. due to IO errors, timeouts or 5xx.
+    Unreachable,
+}
+
+impl Judgement {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::OptIn => "opt_in",
+            Self::OptOut => "opt_out",
+            Self::Unreachable => "unreachable",
+        }
+    }
+}
+
+impl FromStr for Judgement {
+    type Err = BoxError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "opt_in" => Ok(Self::OptIn),
+            "opt_out" => Ok(Self::OptOut),
+            "unreachable" => Ok(Self::Unreachable),
+            _ => Err(format!("unknown judgement {}", s).into()),
+        }
+    }
+}
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
 pub(crate) struct Policy {
-    pub(crate) bip77_allowed: bool,
+    pub(crate) judgement: Judgement,
     pub(crate) expires: Instant,
 }
 
 impl Policy {
-    fn always(bip77_allowed: bool) -> Self {
+    fn always(judgement: Judgement) -> Self {
         // Rationale for thirty years is same as tokio's Instant::far_future,
         // this value is portable and will not overflow for foreseeable future
         const THIRTY_YEARS: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
         let expires = Instant::now() + THIRTY_YEARS;
-        Self { bip77_allowed, expires }
+        Self { judgement, expires }
     }
+
+    #[cfg(test)]
+    pub(crate) fn bip77_allowed(&self) -> bool { self.judgement == Judgement::OptIn }
 }
 
 #[derive(Debug)]
@@ -41,11 +92,25 @@ enum Status {
     Known(Policy),
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub(crate) struct Prober {
     gateways: RwLock<KnownGateways>,
     ttl_config: TTLConfig,
+    timeouts: UpstreamTimeouts,
     client: super::HttpClient,
+    metrics: Metrics,
+}
+
+impl Default for Prober {
+    fn default() -> Self {
+        Self::new(
+            super::HttpClient::default(),
+            DEFAULT_CAPACITY,
+            TTLConfig::default(),
+            UpstreamTimeouts::PROBE,
+            Metrics::default(),
+        )
+    }
 }
 
 #[derive(Debug)]
@@ -71,16 +136,14 @@ impl PartialOrd for HeapEntry {
 }
 
 impl Default for KnownGateways {
-    fn default() -> Self {
-        Self {
-            capacity: DEFAULT_CAPACITY,
-            by_url: HashMap::default(),
-            by_expiry: BinaryHeap::default(),
-        }
-    }
+    fn default() -> Self { Self::with_capacity(DEFAULT_CAPACITY) }
 }
 
 impl KnownGateways {
+    fn with_capacity(capacity: usize) -> Self {
+        Self { capacity, by_url: HashMap::default(), by_expiry: BinaryHeap::default() }
+    }
+
     fn get(&mut self, url: &GatewayUri) -> Option<&Status> {
         // eager pruning because the borrow checker gets upset by the commented
         // out lazy version below
@@ -172,17 +235,155 @@ impl KnownGateways {
 
         Some(())
     }
+
+    /// Known policies which expire within `max_ttl`, one per line after the
+    /// version line, as `<expiry in unix seconds> <judgement> <gateway>`.
+    /// Longer lived policies were asserted rather than probed, and in flight
+    /// probes have no policy yet.
+    fn snapshot(&self, max_ttl: Duration) -> String {
+        let (now, unix_now) = (Instant::now(), SystemTime::now());
+        let mut snapshot = format!("{}\n", SNAPSHOT_VERSION);
+        for (url, status) in &self.by_url {
+            let Status::Known(policy) = status else { continue };
+            let ttl = policy.expires.saturating_duration_since(now);
+            if ttl.is_zero() || ttl > max_ttl {
+                continue;
+            }
+            let expires = (unix_now + ttl).duration_since(UNIX_EPOCH).unwrap_or_default();
+            snapshot.push_str(&format!(
+                "{} {} {}\n",
+                expires.as_secs(),
+                policy.judgement.as_str(),
+                url.to_uri()
+            ));
+        }
+        snapshot
+    }
+
+    /// Insert the policies of a snapshot, discarding expired or corrupt
+    /// entries and capping TTLs at `max_ttl`. Entries for `asserted` are
+    /// skipped, since its policy is asserted rather than probed. Returns how
+    /// many policies were restored.
+    fn restore(
+        &mut self,
+        snapshot: &str,
+        max_ttl: Duration,
+        asserted: &GatewayUri,
+    ) -> Result<usize, BoxError> {
+        let mut lines = snapshot.lines();
+        if lines.next() != Some(SNAPSHOT_VERSION) {
+            return Err("unsupported snapshot version".into());
+        }
+
+        let (now, unix_now) = (Instant::now(), SystemTime::now());
+        let mut restored = 0;
+        for line in lines {
+            let parse = || -> Result<(GatewayUri, Judgement, SystemTime), BoxError> {
+                let mut fields = line.splitn(3, ' ');
+                let mut next = || fields.next().ok_or("missing field");
+                let expires = UNIX_EPOCH + Duration::from_secs(next()?.parse()?);
+                let judgement = next()?.parse()?;
+                Ok((next()?.parse()?, judgement, expires))
+            };
+            let (url, judgement, expires) = match parse() {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!("Discarding corrupt prober snapshot entry {:?}: {}", line, e);
+                    continue;
+                }
+            };
+            let Ok(ttl) = expires.duration_since(unix_now) else { continue };
+            if ttl.is_zero() || url == *asserted || !self.has_capacity() {
+                continue;
+            }
+            let policy = Policy { judgement, expires: now + ttl.min(max_ttl) };
+            if self.insert(&url, policy).is_some() {
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
 }
 
 impl Prober {
-    pub(crate) fn new_with_client(client: super::HttpClient) -> Self {
-        Self { client, ..Self::default() }
+    pub(crate) fn new(
+        client: super::HttpClient,
+        capacity: usize,
+        ttl_config: TTLConfig,
+        timeouts: UpstreamTimeouts,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            gateways: RwLock::new(KnownGateways::with_capacity(capacity)),
+            ttl_config,
+            timeouts,
+            client,
+            metrics,
+        }
+    }
+
+    /// Restore known gateways from a snapshot file written by
+    /// [`Self::snapshot_until`]. A missing file is not an error. A saved
+    /// policy for `default_gateway` is ignored so that it can still be
+    /// asserted opted in.
+    pub(crate) fn load_snapshot(
+        &mut self,
+        path: &Path,
+        default_gateway: &GatewayUri,
+    ) -> Result<usize, BoxError> {
+        let snapshot = match std::fs::read_to_string(path) {
+            Ok(snapshot) => snapshot,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let max_ttl = self.ttl_config.longest();
+        self.gateways.get_mut().restore(&snapshot, max_ttl, default_gateway)
+    }
+
+    /// Write known gateways to a snapshot file, replacing it atomically.
+    pub(crate) async fn save_snapshot(&self, path: &Path) -> Result<(), BoxError> {
+        let snapshot = self.gateways.read().await.snapshot(self.ttl_config.longest());
+        let path = path.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let mut tmp_path = path.clone().into_os_string();
+            tmp_path.push(".tmp");
+            std::fs::write(&tmp_path, snapshot)?;
+            std::fs::rename(&tmp_path, &path)
+        })
+        .await??;
+        Ok(())
+    }
+
+    /// Periodically write known gateways to a snapshot file, and once more
+    /// when `shutdown` is cancelled.
+    pub(crate) async fn snapshot_until(&self, path: &Path, shutdown: CancellationToken) {
+        let mut interval = tokio::time::interval(SNAPSHOT_INTERVAL);
+        interval.tick().await;
+        loop {
+            let stop = tokio::select! {
+                _ = shutdown.cancelled() => true,
+                _ = interval.tick() => false,
+            };
+            if let Err(e) = self.save_snapshot(path).await {
+                error!("Failed to write prober snapshot to {}: {}", path.display(), e);
+            }
+            if stop {
+                info!("Wrote prober snapshot to {}", path.display());
+                return;
+            }
+        }
     }
 
     /// Permanently mark a gateway authority as allowed.
     pub(crate) async fn assert_opt_in(&self, url: &GatewayUri) -> Option<()> {
         let mut locked_map = self.gateways.write().await;
-        locked_map.insert(url, Policy::always(true))
+        locked_map.insert(url, Policy::always(Judgement::OptIn))
+    }
+
+    /// Whether checking a gateway's opt-in would not start a new probe,
+    /// because its policy is known or a probe is already in flight.
+    pub(crate) async fn is_known(&self, url: &GatewayUri) -> bool {
+        self.gateways.write().await.get(url).is_some()
     }
 
     /// Check whether a gateway is allowed. If the policy is not known,
@@ -191,12 +392,22 @@ impl Prober {
         let inflight = {
             let mut locked_map = self.gateways.write().await;
             match locked_map.get(url) {
-                Some(Status::Known(policy)) => return Some(*policy),
-                Some(Status::InFlight(receiver)) => Ok(receiver.clone()),
+                Some(Status::Known(policy)) => {
+                    self.metrics.record_prober_cache(metrics::CACHE_HIT);
+                    return Some(*policy);
+                }
+                Some(Status::InFlight(receiver)) => {
+                    self.metrics.record_prober_cache(metrics::CACHE_IN_FLIGHT_JOIN);
+                    Ok(receiver.clone())
+                }
                 None => {
                     // Only actually query the url if this is the first
                     // lookup and the map is not over capacity
-                    let sender = locked_map.allocate_in_flight(url)?;
+                    let Some(sender) = locked_map.allocate_in_flight(url) else {
+                        self.metrics.record_prober_cache(metrics::CACHE_CAPACITY_REJECTION);
+                        return None;
+                    };
+                    self.metrics.record_prober_cache(metrics::CACHE_MISS);
                     Err(sender)
                 }
             }
@@ -246,8 +457,24 @@ impl Prober {
         None
     }
 
-    /// Probes a target gateway by attempting to send a GET request.
+    /// Probes a target gateway by attempting to send a GET request. Probes
+    /// exceeding the probe timeouts are treated like TCP timeouts.
     async fn probe(&self, base_url: &GatewayUri) -> Policy {
+        self.timeouts
+            .total(self.probe_without_deadline(base_url))
+            .await
+            .unwrap_or_else(|_| self.timed_out())
+    }
+
+    fn timed_out(&self) -> Policy {
+        self.metrics.record_probe("timedout");
+        Policy {
+            judgement: Judgement::Unreachable,
+            expires: Instant::now() + self.ttl_config.timedout,
+        }
+    }
+
+    async fn probe_without_deadline(&self, base_url: &GatewayUri) -> Policy {
         // Create a GET request without a body
         let req = hyper::Request::builder()
             .method(hyper::Method::GET)
@@ -259,56 +486,63 @@ impl Prober {
             ))
             .expect("creating GET request must succeed");
 
-        let mut res = self.client.request(req).await;
-
-        // opt-in is tracked via a separate mutable variable since it only
-        // occurs in the first sub-branch of this large conditional, which is
-        // largely concerned with determining the TTL
-        let mut bip77_allowed = false;
+        let mut res = match self.timeouts.first_byte(self.client.request(req)).await {
+            Ok(res) => res,
+            Err(_) => return self.timed_out(),
+        };
 
         let ttls = &self.ttl_config;
-        let ttl = match &mut res {
+        let (category, judgement, ttl) = match &mut res {
             Ok(res) => {
-                // TODO handle Cache-Control
                 let status = res.status();
+                let header_ttl = ttls.header_ttl(status, res.headers());
 
-                if status.is_success() {
-                    bip77_allowed = Self::is_explicit_opt_in(res).await.is_some();
-
-                    if bip77_allowed {
-                        ttls.opt_in
+                let (category, judgement, ttl) = if status.is_success() {
+                    if Self::is_explicit_opt_in(res).await.is_some() {
+                        ("opt_in", Judgement::OptIn, ttls.opt_in)
                     } else {
-                        ttls.http_2xx
+                        ("http_2xx", Judgement::OptOut, ttls.http_2xx)
                     }
                 } else if status == hyper::StatusCode::GATEWAY_TIMEOUT {
-                    ttls.http_504_gateway_timeout
+                    (
+                        "http_504_gateway_timeout",
+                        Judgement::Unreachable,
+                        ttls.http_504_gateway_timeout,
+                    )
+                } else if status == StatusCode::TOO_MANY_REQUESTS {
+                    // the gateway did not answer the probe, so this is no
+                    // opt-out, and like a 5xx it is likely transient
+                    ("http_429_too_many_requests", Judgement::Unreachable, ttls.http_5xx)
+                } else if status == StatusCode::REQUEST_TIMEOUT {
+                    ("http_408_request_timeout", Judgement::Unreachable, ttls.http_5xx)
                 } else if status.is_client_error() {
-                    // TODO handle Retry-After for 429 too many requests
-                    ttls.http_4xx
+                    ("http_4xx", Judgement::OptOut, ttls.http_4xx)
                 } else if status.is_server_error() {
-                    // TODO handle Retry-After for 503 service unavailable
-                    ttls.http_5xx
+                    ("http_5xx", Judgement::Unreachable, ttls.http_5xx)
                 } else {
-                    ttls.default
-                }
+                    ("default", Judgement::OptOut, ttls.default)
+                };
+                // the gateway knows best how long its answer remains valid
+                (category, judgement, header_ttl.unwrap_or(ttl))
             }
             Err(err) => {
-                if let Some(io_error) =
-                    err.source().and_then(|source| source.downcast_ref::<std::io::Error>())
-                {
-                    match io_error.kind() {
-                        ErrorKind::NotFound => ttls.dns,
-                        ErrorKind::TimedOut => ttls.timedout,
-                        ErrorKind::ConnectionReset => ttls.reset_by_peer,
-                        _ => ttls.default,
-                    }
-                } else {
-                    ttls.default
-                }
+                let (category, judgement, ttl) = match io_error_kind(err) {
+                    Some(ErrorKind::NotFound) => ("dns", Judgement::Unreachable, ttls.dns),
+                    Some(ErrorKind::TimedOut) =>
+                        ("timedout", Judgement::Unreachable, ttls.timedout),
+                    Some(ErrorKind::ConnectionReset) =>
+                        ("reset_by_peer", Judgement::Unreachable, ttls.reset_by_peer),
+                    // the gateway is not a public address, so it must not be relayed to
+                    Some(ErrorKind::PermissionDenied) =>
+                        ("non_public_address", Judgement::OptOut, ttls.default),
+                    _ => ("default", Judgement::Unreachable, ttls.default),
+                };
+                (category, judgement, ttl)
             }
         };
+        self.metrics.record_probe(category);
 
-        Policy { bip77_allowed, expires: Instant::now() + ttl }
+        Policy { judgement, expires: Instant::now() + ttl }
     }
 
     pub(crate) async fn unavailable_for(&self) -> Duration {
@@ -337,34 +571,45 @@ fn parse_alpn_encoded(input: &[u8]) -> std::io::Result<Vec<Vec<u8>>> {
     Ok(output)
 }
 
-#[derive(Debug)]
-struct TTLConfig {
+/// Time to live for cached probe results, by probing result/condition.
+#[derive(Debug, Clone)]
+pub struct TTLConfig {
     /// Explicit opt-in, defaults to LONG.
-    opt_in: Duration,
+    pub opt_in: Duration,
 
     // everything else is an opt-out
     /// Any other 2xx response, for example ohttp-keys which indicate no
     /// opt-in. Defaults to LONG to avoid spamming servers.
-    http_2xx: Duration,
-    /// Any 4xx response, for example 404. Defaults to LONG to avoid
-    /// spamming servers.
-    http_4xx: Duration,
+    pub http_2xx: Duration,
+    /// Any 4xx response, for example 404, other than 408 and 429. Defaults
+    /// to LONG to avoid spamming servers.
+    pub http_4xx: Duration,
     /// TTL for 504 gateway timeout. Defaults to NONE assuming that is transient.
-    http_504_gateway_timeout: Duration,
-    /// Any other 5xx response, for example internal server error. Defaults to
-    /// SHORT.
-    http_5xx: Duration,
+    pub http_504_gateway_timeout: Duration,
+    /// Any other 5xx response, for example internal server error, and 408 or
+    /// 429 responses without `Retry-After`. Defaults to SHORT.
+    pub http_5xx: Duration,
 
     // io errors, should be ephemeral
     ///  TTL for host not found. Defaults to NONE assuming host name resolution and/or DNS resolver cache negative results.
-    dns: Duration,
+    pub dns: Duration,
     ///  TTL for reset by peer errors. Defaults to NONE as that is transient.
-    reset_by_peer: Duration,
+    pub reset_by_peer: Duration,
     ///  TTL for tcp timeout. Defaults to NONE as that is transient.
-    timedout: Duration,
+    pub timedout: Duration,
 
     /// For other errors, default to SHORT enforce rudimentary rate limiting
-    default: Duration,
+    pub default: Duration,
+
+    // bounds for TTLs requested by the gateway, which take precedence over
+    // the TTLs above
+    /// Lower bound for TTLs from `Cache-Control` or `Retry-After` headers.
+    /// Defaults to SHORT so that gateways can not make the relay probe them on
+    /// every request.
+    pub header_min: Duration,
+    /// Upper bound for TTLs from `Cache-Control` or `Retry-After` headers.
+    /// Defaults to LONG.
+    pub header_max: Duration,
 }
 
 /// Different probing results/conditions and the time to live when caching that
@@ -388,6 +633,77 @@ impl Default for TTLConfig {
             reset_by_peer: NONE,
             timedout: NONE,
             default: SHORT,
+            header_min: SHORT,
+            header_max: LONG,
+        }
+    }
+}
+
+impl TTLConfig {
+    /// The longest TTL any probe result can be cached for.
+    fn longest(&self) -> Duration {
+        [
+            self.opt_in,
+            self.http_2xx,
+            self.http_4xx,
+            self.http_504_gateway_timeout,
+            self.http_5xx,
+            self.dns,
+            self.reset_by_peer,
+            self.timedout,
+            self.default,
+            self.header_max,
+        ]
+        .into_iter()
+        .max()
+        .unwrap_or_default()
+    }
+
+    /// The TTL the gateway asks for, if any: `Retry-After` when it is rate
+    /// limiting or unavailable, otherwise how long its response is fresh
+    /// according to `Cache-Control`. Clamped to the header bounds.
+    fn header_ttl(&self, status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+        let ttl = match status {
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE =>
+                parse_retry_after(headers),
+            _ => None,
+        }
+        .or_else(|| parse_cache_control(headers))?;
+        Some(ttl.max(self.header_min).min(self.header_max))
+    }
+}
+
+/// The freshness lifetime according to `Cache-Control`. The relay is a shared
+/// cache, so `s-maxage` takes precedence over `max-age`.
+pub(crate) fn parse_cache_control(headers: &HeaderMap) -> Option<Duration> {
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for value in headers.get_all(CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let (name, argument) = match directive.trim().split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            let seconds = || argument?.parse::<u64>().ok().map(Duration::from_secs);
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "no-cache" => return Some(Duration::ZERO),
+                "max-age" => max_age = seconds(),
+                "s-maxage" => s_maxage = seconds(),
+                _ => {}
+            }
+        }
+    }
+    s_maxage.or(max_age)
+}
+
+/// `Retry-After` as either a number of seconds or an HTTP date.
+fn parse_retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    match value.parse::<u64>() {
+        Ok(seconds) => Some(Duration::from_secs(seconds)),
+        Err(_) => {
+            let date = httpdate::parse_http_date(value).ok()?;
+            Some(date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
         }
     }
 }
@@ -410,9 +726,7 @@ mod tests {
 
     #[tokio::test(start_paused = true)]
     async fn test_known_gateways() {
-        let mut db = KnownGateways::default();
-
-        db.capacity = 1;
+        let mut db = KnownGateways::with_capacity(1);
 
         let url = GatewayUri::from_static("https://payjo.in");
 
@@ -420,7 +734,7 @@ mod tests {
         assert!(db.no_capacity_for().is_zero(), "capacity should be available right now");
         assert!(db.get(&url).is_none(), "mock gateway should not yet be known");
 
-        let policy = Policy { bip77_allowed: true, expires: Instant::now() + TIMESTEP };
+        let policy = Policy { judgement: Judgement::OptIn, expires: Instant::now() + TIMESTEP };
 
         // see comment in implementation of insert(), arguably this should not
         // be allowed as the state machine should start with inflight, but this
@@ -439,8 +753,11 @@ mod tests {
             "allocating inflight future for known gateway should fail"
         );
         assert!(
-            db.insert(&url, Policy { bip77_allowed: false, expires: Instant::now() + TIMESTEP })
-                .is_none(),
+            db.insert(
+                &url,
+                Policy { judgement: Judgement::OptOut, expires: Instant::now() + TIMESTEP }
+            )
+            .is_none(),
             "inserting a duplicate policy entry should fail"
         );
         if let Some(Status::Known(got)) = db.get(&url) {
@@ -461,7 +778,8 @@ mod tests {
 
         // Insert expired
         assert!(
-            db.insert(&url, Policy { bip77_allowed: false, expires: Instant::now() }).is_some(),
+            db.insert(&url, Policy { judgement: Judgement::OptOut, expires: Instant::now() })
+                .is_some(),
             "inserting an expired entry should not fail"
         );
         assert!(
@@ -497,8 +815,11 @@ mod tests {
             "with an inflight entry, known gateway set should be at capacity"
         );
         assert!(
-            db.insert(&url, Policy { bip77_allowed: true, expires: Instant::now() + TIMESTEP })
-                .is_some(),
+            db.insert(
+                &url,
+                Policy { judgement: Judgement::OptIn, expires: Instant::now() + TIMESTEP }
+            )
+            .is_some(),
             "inserting known entry to overwrite inflight one should succeed even at capacity"
         );
 
@@ -520,7 +841,7 @@ mod tests {
         assert!(
             db.insert(
                 &url_2,
-                Policy { bip77_allowed: false, expires: Instant::now() + (2 * TIMESTEP) }
+                Policy { judgement: Judgement::OptOut, expires: Instant::now() + (2 * TIMESTEP) }
             )
             .is_some(),
             "inserting second entry should succeed"
@@ -570,13 +891,105 @@ mod tests {
 
         // test happy path
         let status = prober.check_opt_in(&url).await.expect("probing must succeed");
-        assert!(status.bip77_allowed, "mock gateway opt-in should have been detected");
+        assert!(status.bip77_allowed(), "mock gateway opt-in should have been detected");
         mock_opt_in.assert();
         drop(mock_opt_in);
 
         // test cached result, mockit server will cause failure if another GET query is sent
         let status = prober.check_opt_in(&url).await.expect("second probe must succeed");
-        assert!(status.bip77_allowed, "gateway opt-in should be cached");
+        assert!(status.bip77_allowed(), "gateway opt-in should be cached");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        const WEEK: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+        let opted_in = GatewayUri::from_static("https://payjo.in");
+        let opted_out = GatewayUri::from_static("http://example.com:8080");
+        let asserted = GatewayUri::from_static("https://default.example");
+
+        let mut db = KnownGateways::default();
+        db.insert(
+            &opted_in,
+            Policy { judgement: Judgement::OptIn, expires: Instant::now() + WEEK },
+        );
+        db.insert(
+            &opted_out,
+            Policy { judgement: Judgement::OptOut, expires: Instant::now() + WEEK },
+        );
+        db.insert(&asserted, Policy::always(Judgement::OptIn));
+        let snapshot = db.snapshot(WEEK);
+        assert!(snapshot.starts_with(SNAPSHOT_VERSION), "snapshot should be versioned");
+        assert!(!snapshot.contains("default.example"), "asserted policies should not be saved");
+
+        let expired = "1 opt_in https://expired.example:443/\n";
+        let corrupt = "not a snapshot entry\n123 opt_maybe https://corrupt.example/\n";
+        let mut restored = KnownGateways::default();
+        assert_eq!(
+            restored
+                .restore(&(snapshot + expired + corrupt), WEEK, &asserted)
+                .expect("snapshot is valid"),
+            2,
+            "expired and corrupt entries should be discarded"
+        );
+        for (url, judgement) in [(&opted_in, Judgement::OptIn), (&opted_out, Judgement::OptOut)] {
+            match restored.get(url) {
+                Some(Status::Known(policy)) => {
+                    assert_eq!(policy.judgement, judgement);
+                    assert!(policy.expires > Instant::now() + WEEK - Duration::from_secs(2));
+                }
+                status => panic!("policy should be restored, got {:?}", status),
+            }
+        }
+
+        let mut capped = KnownGateways::default();
+        capped.restore(&db.snapshot(WEEK), TIMESTEP, &asserted).expect("snapshot is valid");
+        match capped.get(&opted_in) {
+            Some(Status::Known(policy)) =>
+                assert!(policy.expires <= Instant::now() + TIMESTEP, "TTL should be capped"),
+            status => panic!("policy should be restored, got {:?}", status),
+        }
+
+        let mut db = KnownGateways::default();
+        assert!(
+            db.restore("ohttp-relay prober snapshot v0\n", WEEK, &asserted).is_err(),
+            "unknown versions should be rejected"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("prober-snapshot");
+        let url = GatewayUri::from_static("https://payjo.in");
+        let default_gateway = GatewayUri::from_static("https://default.example");
+
+        let mut prober = Prober::default();
+        assert_eq!(
+            prober
+                .load_snapshot(&path, &default_gateway)
+                .expect("missing snapshot is not an error"),
+            0
+        );
+        prober.gateways.write().await.insert(
+            &url,
+            Policy { judgement: Judgement::OptIn, expires: Instant::now() + TIMESTEP },
+        );
+        prober.gateways.write().await.insert(
+            &default_gateway,
+            Policy { judgement: Judgement::OptOut, expires: Instant::now() + TIMESTEP },
+        );
+        prober.save_snapshot(&path).await.expect("snapshot should be written");
+
+        let mut prober = Prober::default();
+        assert_eq!(
+            prober.load_snapshot(&path, &default_gateway).expect("snapshot should be read"),
+            1,
+            "the default gateway's saved policy should be skipped"
+        );
+        assert!(prober.is_known(&url).await, "restored gateway should not be probed again");
+        prober.assert_opt_in(&default_gateway).await.expect("default gateway should be asserted");
+        let policy = prober.check_opt_in(&default_gateway).await.expect("policy is known");
+        assert!(policy.bip77_allowed(), "default gateway should be opted in despite the snapshot");
     }
 
     #[tokio::test]
@@ -596,7 +1009,7 @@ mod tests {
 
         // test happy path
         let status = prober.check_opt_in(&url).await.expect("probing must succeed");
-        assert!(status.bip77_allowed, "asserte opt-in should be cached");
+        assert!(status.bip77_allowed(), "asserte opt-in should be cached");
     }
 
     #[tokio::test]
@@ -617,7 +1030,7 @@ mod tests {
         let status = prober.check_opt_in(&url).await.expect("probing must succeed");
         mock_only_rfc_9540.assert();
         assert!(
-            !status.bip77_allowed,
+            !status.bip77_allowed(),
             "RFC 9540 gateway which doesn't signal should not be considered opted-in"
         );
     }
@@ -639,7 +1052,188 @@ mod tests {
 
         let status = prober.check_opt_in(&url).await.expect("probing must succeed");
         mock_not_found.assert();
-        assert!(!status.bip77_allowed, "non-existent gateway should not be considered opt-in");
+        assert_eq!(
+            status.judgement,
+            Judgement::OptOut,
+            "non-existent gateway should be considered an opt-out"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_header_ttl() {
+        let mut server = Server::new_async().await;
+        let url =
+            GatewayUri::from_str(&server.url()).expect("must be able to parse mock server URL");
+
+        let prober = Prober::default();
+
+        let mock_opt_in = server
+            .mock("GET", RFC_9540_GATEWAY_PATH)
+            .match_query(mockito::Matcher::Regex("^allowed_purposes$".into()))
+            .with_header(hyper::header::CONTENT_TYPE.as_str(), ALLOWED_PURPOSES_CONTENT_TYPE)
+            .with_header(hyper::header::CACHE_CONTROL.as_str(), "public, max-age=60")
+            .with_body(BIP77_OPT_IN_RESPONSE)
+            .create();
+
+        let before = Instant::now();
+        let status = prober.check_opt_in(&url).await.expect("probing must succeed");
+        mock_opt_in.assert();
+        assert!(status.bip77_allowed(), "mock gateway opt-in should have been detected");
+        let ttl = status.expires - before;
+        assert!(
+            ttl >= Duration::from_secs(60) && ttl < Duration::from_secs(61),
+            "Cache-Control max-age should determine the TTL, got {:?}",
+            ttl
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_retry_after() {
+        let mut server = Server::new_async().await;
+        let url =
+            GatewayUri::from_str(&server.url()).expect("must be able to parse mock server URL");
+
+        let prober = Prober::default();
+
+        let mock_unavailable = server
+            .mock("GET", RFC_9540_GATEWAY_PATH)
+            .match_query(mockito::Matcher::Regex("^allowed_purposes$".into()))
+            .with_status(503)
+            .with_header(hyper::header::RETRY_AFTER.as_str(), "120")
+            .with_header(hyper::header::CACHE_CONTROL.as_str(), "max-age=60")
+            .create();
+
+        let before = Instant::now();
+        let status = prober.check_opt_in(&url).await.expect("probing must succeed");
+        mock_unavailable.assert();
+        assert_eq!(
+            status.judgement,
+            Judgement::Unreachable,
+            "unavailable gateway should be considered unreachable"
+        );
+        let ttl = status.expires - before;
+        assert!(
+            ttl >= Duration::from_secs(120) && ttl < Duration::from_secs(121),
+            "Retry-After should take precedence for 503, got {:?}",
+            ttl
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mock_rate_limited() {
+        let mut server = Server::new_async().await;
+        let url =
+            GatewayUri::from_str(&server.url()).expect("must be able to parse mock server URL");
+
+        let prober = Prober::default();
+
+        let mock_rate_limited = server
+            .mock("GET", RFC_9540_GATEWAY_PATH)
+            .match_query(mockito::Matcher::Regex("^allowed_purposes$".into()))
+            .with_status(429)
+            .create();
+
+        let before = Instant::now();
+        let status = prober.check_opt_in(&url).await.expect("probing must succeed");
+        mock_rate_limited.assert();
+        assert_eq!(status.judgement, Judgement::Unreachable);
+        let ttl = status.expires - before;
+        assert!(
+            ttl < TTLConfig::default().http_5xx + Duration::from_secs(1),
+            "rate limiting without Retry-After should be treated as transient, got {:?}",
+            ttl
+        );
+    }
+
+    #[test]
+    fn test_header_ttl() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.append(*name, hyper::header::HeaderValue::from_static(value));
+            }
+            headers
+        };
+        let ttls = TTLConfig {
+            header_min: Duration::from_secs(10),
+            header_max: Duration::from_secs(3600),
+            ..TTLConfig::default()
+        };
+
+        assert_eq!(ttls.header_ttl(StatusCode::OK, &HeaderMap::new()), None);
+        assert_eq!(
+            ttls.header_ttl(StatusCode::OK, &headers(&[("cache-control", "max-age=60")])),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            ttls.header_ttl(
+                StatusCode::OK,
+                &headers(&[("cache-control", "max-age=60"), ("cache-control", "s-maxage=120")])
+            ),
+            Some(Duration::from_secs(120)),
+            "s-maxage should take precedence"
+        );
+        assert_eq!(
+            ttls.header_ttl(StatusCode::NOT_FOUND, &headers(&[("cache-control", "no-store")])),
+            Some(Duration::from_secs(10)),
+            "no-store should be clamped to the minimum"
+        );
+        assert_eq!(
+            ttls.header_ttl(StatusCode::OK, &headers(&[("cache-control", "MAX-AGE=\"31536000\"")])),
+            Some(Duration::from_secs(3600)),
+            "long max-age should be clamped to the maximum"
+        );
+        assert_eq!(
+            ttls.header_ttl(StatusCode::OK, &headers(&[("retry-after", "60")])),
+            None,
+            "Retry-After should only apply to 429 and 503"
+        );
+        assert_eq!(
+            ttls.header_ttl(StatusCode::TOO_MANY_REQUESTS, &headers(&[("retry-after", "60")])),
+            Some(Duration::from_secs(60))
+        );
+        let date = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(600));
+        let mut retry_at = HeaderMap::new();
+        retry_at.insert(RETRY_AFTER, date.parse().unwrap());
+        let ttl = ttls.header_ttl(StatusCode::SERVICE_UNAVAILABLE, &retry_at).unwrap();
+        assert!(ttl > Duration::from_secs(590) && ttl <= Duration::from_secs(600));
+        assert_eq!(
+            ttls.header_ttl(StatusCode::SERVICE_UNAVAILABLE, &headers(&[("retry-after", "soon")])),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_probe_timeout() {
+        // accepts connections but never responds
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = GatewayUri::from_str(&format!("http://{}", listener.local_addr().unwrap()))
+            .expect("must be able to parse listener address");
+
+        let ttl_config = TTLConfig { timedout: Duration::from_secs(60), ..TTLConfig::default() };
+        let timeouts = UpstreamTimeouts {
+            first_byte: Some(Duration::from_millis(100)),
+            ..UpstreamTimeouts::PROBE
+        };
+        let prober = Prober::new(
+            crate::HttpClient::default(),
+            DEFAULT_CAPACITY,
+            ttl_config,
+            timeouts,
+            Metrics::default(),
+        );
+
+        let start = Instant::now();
+        let status = prober.check_opt_in(&url).await.expect("probing must succeed");
+        assert_eq!(
+            status.judgement,
+            Judgement::Unreachable,
+            "unresponsive gateway should be considered unreachable"
+        );
+        assert!(
+            status.expires >= start + Duration::from_secs(60),
+            "probe exceeding the timeout should be cached for the timed out TTL"
+        );
     }
 
     #[tokio::test]
@@ -710,11 +1304,11 @@ mod tests {
 
         mock_delayed.assert();
         assert!(
-            a.expect("probe must succeed").bip77_allowed,
+            a.expect("probe must succeed").bip77_allowed(),
             "first concurrent request should detect opt-in"
         );
         assert!(
-            b.expect("probe must succeed").bip77_allowed,
+            b.expect("probe must succeed").bip77_allowed(),
             "second concurrent request should detect opt-in"
         );
         assert_eq!(*counter.lock().unwrap(), 1, "requests should have been deduplicated");
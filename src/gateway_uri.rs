@@ -41,6 +41,8 @@ impl GatewayUri {
             .expect("gateway URI must consist of a scheme and authority only")
     }
 
+    pub(crate) fn authority(&self) -> &Authority { &self.authority }
+
     fn to_uri_builder(&self) -> http::uri::Builder {
         Uri::builder().scheme(self.scheme.clone()).authority(self.authority.clone())
     }
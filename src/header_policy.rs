@@ -0,0 +1,142 @@
+//! Headers of requests the relay makes to gateways.
+//!
+//! Outbound requests never carry anything the client sent besides the
+//! encapsulated message's `Content-Type` and `Content-Length`, or the `Accept`
+//! of the relay's own requests for gateway keys. In particular
+//! client addresses, `Forwarded`, `X-Forwarded-For`, cookies and user agents are
+//! not forwarded, so the gateway can not tell clients of the same relay apart.
+
+use hyper::header::{HeaderName, HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, USER_AGENT};
+use hyper::HeaderMap;
+
+use crate::error::BoxError;
+
+/// The `User-Agent` sent to gateways unless configured otherwise.
+pub const DEFAULT_USER_AGENT: HeaderValue =
+    HeaderValue::from_static(concat!("ohttp-relay/", env!("CARGO_PKG_VERSION")));
+
+/// Headers which may not be configured as extra headers, because they could
+/// identify a client or are set by the relay itself.
+const RESERVED_HEADERS: &[&str] = &[
+    "accept",
+    "content-type",
+    "content-length",
+    "host",
+    "user-agent",
+    "cookie",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-real-ip",
+    "connection",
+    "te",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Headers set on every request to a gateway, see the [module
+/// documentation](self).
+#[derive(Debug, Clone)]
+pub struct HeaderPolicy {
+    user_agent: HeaderValue,
+    extra_headers: HeaderMap,
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        Self { user_agent: DEFAULT_USER_AGENT, extra_headers: HeaderMap::new() }
+    }
+}
+
+impl HeaderPolicy {
+    /// The `User-Agent` sent on behalf of all clients. An empty value sends an
+    /// explicitly empty header. Defaults to [`DEFAULT_USER_AGENT`].
+    pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Add a static header to every request, e.g. `Via` or a token the gateway
+    /// requires to accept requests from this relay. Fails for headers which could
+    /// identify a client or which the relay sets itself.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Result<Self, BoxError> {
+        if RESERVED_HEADERS.contains(&name.as_str()) {
+            return Err(format!("{} can not be set as an extra header", name).into());
+        }
+        self.extra_headers.append(name, value);
+        Ok(self)
+    }
+
+    /// Replace `headers` with the headers permitted by this policy.
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        let content_type = headers.remove(CONTENT_TYPE);
+        let content_length = headers.remove(CONTENT_LENGTH);
+        let accept = headers.remove(ACCEPT);
+        headers.clear();
+        if let Some(content_type) = content_type {
+            headers.insert(CONTENT_TYPE, content_type);
+        }
+        if let Some(content_length) = content_length {
+            headers.insert(CONTENT_LENGTH, content_length);
+        }
+        if let Some(accept) = accept {
+            headers.insert(ACCEPT, accept);
+        }
+        headers.insert(USER_AGENT, self.user_agent.clone());
+        for (name, value) in &self.extra_headers {
+            headers.append(name, value.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{COOKIE, FORWARDED};
+
+    use super::*;
+
+    #[test]
+    fn test_apply_strips_client_metadata() {
+        let policy = HeaderPolicy::default()
+            .header(HeaderName::from_static("via"), HeaderValue::from_static("1.1 relay"))
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-req"));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("42"));
+        headers.insert(USER_AGENT, HeaderValue::from_static("curl/8.0"));
+        headers.insert(COOKIE, HeaderValue::from_static("id=client"));
+        headers.insert(FORWARDED, HeaderValue::from_static("for=192.0.2.1"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("192.0.2.1"));
+        headers.insert("x-real-ip", HeaderValue::from_static("192.0.2.1"));
+
+        policy.apply(&mut headers);
+
+        assert_eq!(headers.len(), 4);
+        assert_eq!(headers[CONTENT_TYPE], "message/ohttp-req");
+        assert_eq!(headers[CONTENT_LENGTH], "42");
+        assert_eq!(headers[USER_AGENT], DEFAULT_USER_AGENT);
+        assert_eq!(headers["via"], "1.1 relay");
+    }
+
+    #[test]
+    fn test_empty_user_agent() {
+        let policy = HeaderPolicy::default().user_agent(HeaderValue::from_static(""));
+        let mut headers = HeaderMap::new();
+        policy.apply(&mut headers);
+        assert_eq!(headers[USER_AGENT], "");
+    }
+
+    #[test]
+    fn test_reserved_headers_rejected() {
+        for name in RESERVED_HEADERS {
+            let name = HeaderName::from_static(name);
+            assert!(HeaderPolicy::default()
+                .header(name, HeaderValue::from_static("value"))
+                .is_err());
+        }
+        assert!(HeaderPolicy::default()
+            .header(HeaderName::from_static("authorization"), HeaderValue::from_static("token"))
+            .is_ok());
+    }
+}
@@ -0,0 +1,460 @@
+//! Serve gateways' `application/ohttp-keys` from the relay, for clients which
+//! can not bootstrap keys through a tunnel to the gateway and accept trusting
+//! the relay with key distribution.
+//!
+//! Keys are fetched from the gateway's RFC 9540 URL and cached for as long as
+//! the gateway's `Cache-Control` allows. Headers the gateway authenticates its
+//! keys with are served along with them.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use http_body_util::combinators::BoxBody;
+use http_body_util::{BodyExt, Limited};
+use hyper::body::Bytes;
+use hyper::header::{HeaderName, HeaderValue, ACCEPT, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{HeaderMap, Method, Request, Response, StatusCode};
+use tokio::sync::OnceCell;
+use tokio::time::Instant;
+use tracing::{instrument, warn};
+
+use crate::error::{self, ceil_secs, Error};
+use crate::gateway_prober::parse_cache_control;
+use crate::gateway_uri::GatewayUri;
+use crate::timeouts::UpstreamTimeouts;
+use crate::{empty, full, HttpClient};
+
+pub(crate) const OHTTP_KEYS_MEDIA_TYPE: HeaderValue =
+    HeaderValue::from_static("application/ohttp-keys");
+
+/// The path keys are served at: `/ohttp-keys` for the default gateway and
+/// e.g. `/ohttp-keys/https://payjo.in` for others.
+pub const KEYS_PATH: &str = "/ohttp-keys";
+
+/// Key configurations are a few hundred bytes, anything much larger is not
+/// a key configuration.
+const MAX_KEYS_SIZE: usize = 16 * 1024;
+
+/// Headers gateways may sign or digest their keys with, see RFC 9421 and
+/// RFC 9530.
+const AUTHENTICATION_HEADERS: [&str; 5] =
+    ["signature", "signature-input", "content-digest", "repr-digest", "digest"];
+
+/// How gateways' keys are cached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCacheConfig {
+    /// Maximum number of gateways whose keys are cached at once.
+    pub capacity: usize,
+    /// How long keys are cached if the gateway does not send `Cache-Control`.
+    pub default_ttl: Duration,
+    /// The longest keys are cached, whatever the gateway's `Cache-Control`.
+    pub max_ttl: Duration,
+}
+
+impl Default for KeyCacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1000,
+            default_ttl: Duration::from_secs(5 * 60),
+            max_ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// The gateway path of a request for keys, e.g. `/https://payjo.in` or the
+/// empty path for the default gateway, if `path` is a request for keys.
+pub(crate) fn gateway_path(path: &str) -> Option<&str> {
+    path.strip_prefix(KEYS_PATH).filter(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[derive(Debug)]
+pub(crate) struct KeyCache {
+    client: HttpClient,
+    config: KeyCacheConfig,
+    timeouts: UpstreamTimeouts,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug, Clone)]
+struct Keys {
+    body: Bytes,
+    authentication: HeaderMap,
+    expires: Instant,
+}
+
+/// A fetch of a gateway's keys, shared by every request for them while it
+/// is in flight.
+type Fetch = Arc<OnceCell<Result<Keys, Error>>>;
+
+#[derive(Debug, Default)]
+struct Entries {
+    fresh: HashMap<GatewayUri, Keys>,
+    by_expiry: BinaryHeap<HeapEntry>,
+    in_flight: HashMap<GatewayUri, Fetch>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct HeapEntry {
+    expires: Instant,
+    key: GatewayUri,
+}
+
+impl Ord for HeapEntry {
+    /// Reverse ordering by expires for min-heap semantics
+    fn cmp(&self, other: &Self) -> Ordering { Reverse(self.expires).cmp(&Reverse(other.expires)) }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl KeyCache {
+    pub(crate) fn new(
+        client: HttpClient,
+        config: KeyCacheConfig,
+        timeouts: UpstreamTimeouts,
+    ) -> Self {
+        Self { client, config, timeouts, entries: Mutex::default() }
+    }
+
+    /// The gateway's keys, from the cache if they are still fresh. Requests
+    /// for keys which are not cached share a single fetch.
+    #[instrument(skip(self))]
+    pub(crate) async fn get(
+        &self,
+        gateway: &GatewayUri,
+    ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+        let fetch = {
+            let mut entries = self.entries.lock().expect("lock should not be poisoned");
+            let now = Instant::now();
+            entries.prune(now);
+            if let Some(keys) = entries.fresh.get(gateway) {
+                return Ok(keys.to_response(now));
+            }
+            entries.in_flight.entry(gateway.clone()).or_default().clone()
+        };
+        let waiting = Waiting { entries: &self.entries, gateway, fetch };
+
+        // if the request which started the fetch gives up, the next one
+        // waiting takes it over
+        let keys = match waiting.fetch.get_or_init(|| self.fetch(gateway)).await {
+            Ok(keys) => keys.clone(),
+            Err(Error::GatewayTimeout) => return Err(Error::GatewayTimeout),
+            Err(_) => return Err(Error::BadGateway),
+        };
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("lock should not be poisoned");
+        if entries.in_flight.get(gateway).is_some_and(|fetch| Arc::ptr_eq(fetch, &waiting.fetch)) {
+            entries.in_flight.remove(gateway);
+            if keys.expires > now {
+                entries.insert(gateway, keys.clone(), self.config.capacity);
+            }
+        }
+        Ok(keys.to_response(now))
+    }
+
+    async fn fetch(&self, gateway: &GatewayUri) -> Result<Keys, Error> {
+        let (headers, body) = fetch_keys(&self.client, &self.timeouts, gateway).await?;
+        let ttl = parse_cache_control(&headers)
+            .unwrap_or(self.config.default_ttl)
+            .min(self.config.max_ttl);
+        let mut authentication = HeaderMap::new();
+        for name in AUTHENTICATION_HEADERS {
+            for value in headers.get_all(name) {
+                authentication.append(HeaderName::from_static(name), value.clone());
+            }
+        }
+        Ok(Keys { body, authentication, expires: Instant::now() + ttl })
+    }
+}
+
+/// Fetch a gateway's keys from its RFC 9540 URL within `timeouts`, along with
+/// the headers of the response.
+pub(crate) async fn fetch_keys(
+    client: &HttpClient,
+    timeouts: &UpstreamTimeouts,
+    gateway: &GatewayUri,
+) -> Result<(HeaderMap, Bytes), Error> {
+    let req = Request::builder()
+        .method(Method::GET)
+        .uri(gateway.rfc_9540_url())
+        .header(ACCEPT, OHTTP_KEYS_MEDIA_TYPE)
+        .body(empty())
+        .map_err(|e| Error::InternalServerError(Box::new(e)))?;
+    let exchange = async {
+        let res = timeouts
+            .first_byte(client.request(req))
+            .await
+            .map_err(|_| Error::GatewayTimeout)?
+            .map_err(|e| match error::io_error_kind(&e) {
+                Some(std::io::ErrorKind::TimedOut) => Error::GatewayTimeout,
+                _ => Error::BadGateway,
+            })?;
+        if res.status() != StatusCode::OK {
+            warn!("Gateway responded to key request with status {}", res.status());
+            return Err(Error::BadGateway);
+        }
+        if res.headers().get(CONTENT_TYPE) != Some(&OHTTP_KEYS_MEDIA_TYPE) {
+            warn!("Gateway responded with keys of type {:?}", res.headers().get(CONTENT_TYPE));
+            return Err(Error::BadGateway);
+        }
+        let (head, body) = res.into_parts();
+        let body = Limited::new(body, MAX_KEYS_SIZE).collect().await.map_err(|e| {
+            warn!("Failed to read keys from gateway: {}", e);
+            Error::BadGateway
+        })?;
+        Ok((head.headers, body.to_bytes()))
+    };
+    timeouts.total(exchange).await.map_err(|_| Error::GatewayTimeout)?
+}
+
+impl Entries {
+    /// Forget keys which expired by `now`.
+    fn prune(&mut self, now: Instant) {
+        while self.by_expiry.peek().is_some_and(|entry| entry.expires <= now) {
+            self.pop();
+        }
+    }
+
+    /// Forget the keys which expire first.
+    fn evict_soonest(&mut self) {
+        while !self.by_expiry.is_empty() {
+            if self.pop() {
+                return;
+            }
+        }
+    }
+
+    /// Pop the soonest expiry, forgetting its keys unless they were replaced
+    /// since. Returns whether keys were forgotten.
+    fn pop(&mut self) -> bool {
+        let Some(entry) = self.by_expiry.pop() else { return false };
+        let current = self.fresh.get(&entry.key).is_some_and(|keys| keys.expires == entry.expires);
+        if current {
+            self.fresh.remove(&entry.key);
+        }
+        current
+    }
+
+    fn insert(&mut self, gateway: &GatewayUri, keys: Keys, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        if !self.fresh.contains_key(gateway) && self.fresh.len() >= capacity {
+            // make room by evicting the keys which would expire first
+            self.evict_soonest();
+        }
+        self.by_expiry.push(HeapEntry { expires: keys.expires, key: gateway.clone() });
+        self.fresh.insert(gateway.clone(), keys);
+    }
+}
+
+/// A request waiting for a [`Fetch`]. A fetch which every request waiting
+/// for gave up on before it finished is forgotten, so that fetches abandoned
+/// by clients do not pile up.
+struct Waiting<'a> {
+    entries: &'a Mutex<Entries>,
+    gateway: &'a GatewayUri,
+    fetch: Fetch,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().expect("lock should not be poisoned");
+        // one reference is the in flight entry's, the other this request's
+        if Arc::strong_count(&self.fetch) == 2
+            && entries.in_flight.get(self.gateway).is_some_and(|f| Arc::ptr_eq(f, &self.fetch))
+        {
+            entries.in_flight.remove(self.gateway);
+        }
+    }
+}
+
+impl Keys {
+    /// Clients and caches in front of the relay may keep the keys for as
+    /// long as the relay does.
+    fn to_response(&self, now: Instant) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut res = Response::new(full(self.body.clone()));
+        let headers = res.headers_mut();
+        headers.extend(self.authentication.clone());
+        headers.insert(CONTENT_TYPE, OHTTP_KEYS_MEDIA_TYPE);
+        headers.insert(CONTENT_LENGTH, HeaderValue::from(self.body.len()));
+        let cache_control = match ceil_secs(self.expires.saturating_duration_since(now)) {
+            0 => HeaderValue::from_static("no-store"),
+            max_age => HeaderValue::from_str(&format!("max-age={}", max_age))
+                .expect("header value should always be valid"),
+        };
+        headers.insert(CACHE_CONTROL, cache_control);
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    const KEYS: &[u8] = b"\x00\x01keys";
+
+    fn key_cache(config: KeyCacheConfig) -> KeyCache {
+        KeyCache::new(HttpClient::default(), config, UpstreamTimeouts::PROBE)
+    }
+
+    async fn body(res: Response<BoxBody<Bytes, hyper::Error>>) -> Bytes {
+        res.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[test]
+    fn test_gateway_path() {
+        assert_eq!(gateway_path("/ohttp-keys"), Some(""));
+        assert_eq!(gateway_path("/ohttp-keys/https://payjo.in"), Some("/https://payjo.in"));
+        assert_eq!(gateway_path("/ohttp-keysx"), None);
+        assert_eq!(gateway_path("/https://payjo.in"), None);
+    }
+
+    #[tokio::test]
+    async fn test_caches_keys() {
+        let mut server = mockito::Server::new_async().await;
+        let gateway = GatewayUri::from_str(&server.url()).unwrap();
+        let keys = server
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .match_header("accept", "application/ohttp-keys")
+            .with_header("content-type", "application/ohttp-keys")
+            .with_header("cache-control", "max-age=60")
+            .with_header("content-digest", "sha-256=:digest:")
+            .with_header("server", "gateway")
+            .with_body(KEYS)
+            .expect(1)
+            .create_async()
+            .await;
+        let cache = key_cache(KeyCacheConfig::default());
+
+        for _ in 0..2 {
+            let res = cache.get(&gateway).await.unwrap();
+            assert_eq!(res.headers()[CONTENT_TYPE], OHTTP_KEYS_MEDIA_TYPE);
+            assert_eq!(res.headers()["content-digest"], "sha-256=:digest:");
+            assert!(
+                res.headers().get("server").is_none(),
+                "only authentication headers are served"
+            );
+            let max_age = res.headers()[CACHE_CONTROL].to_str().unwrap().to_string();
+            assert!(["max-age=60", "max-age=59"].contains(&max_age.as_str()), "got {}", max_age);
+            assert_eq!(body(res).await, KEYS);
+        }
+        keys.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_coalesces_fetches() {
+        let mut server = mockito::Server::new_async().await;
+        let gateway = GatewayUri::from_str(&server.url()).unwrap();
+        let keys = server
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "application/ohttp-keys")
+            .with_header("cache-control", "no-store")
+            .with_body(KEYS)
+            .expect(1)
+            .create_async()
+            .await;
+        let cache = key_cache(KeyCacheConfig::default());
+
+        let (first, second, third) =
+            tokio::join!(cache.get(&gateway), cache.get(&gateway), cache.get(&gateway));
+        for res in [first, second, third] {
+            assert_eq!(body(res.unwrap()).await, KEYS);
+        }
+        keys.assert_async().await;
+        assert!(cache.entries.lock().unwrap().in_flight.is_empty());
+
+        tokio::select! {
+            biased;
+            _ = cache.get(&gateway) => panic!("the fetch should not finish without yielding"),
+            _ = std::future::ready(()) => {}
+        }
+        assert!(
+            cache.entries.lock().unwrap().in_flight.is_empty(),
+            "abandoned fetches should be forgotten"
+        );
+    }
+
+    #[test]
+    fn test_evicts_soonest_expiry() {
+        let gateway = |port| GatewayUri::from_str(&format!("http://127.0.0.1:{}", port)).unwrap();
+        let keys = |ttl| Keys {
+            body: Bytes::from_static(KEYS),
+            authentication: HeaderMap::new(),
+            expires: Instant::now() + Duration::from_secs(ttl),
+        };
+        let mut entries = Entries::default();
+        entries.insert(&gateway(1), keys(30), 2);
+        entries.insert(&gateway(2), keys(10), 2);
+        entries.insert(&gateway(3), keys(20), 2);
+        assert!(entries.fresh.contains_key(&gateway(1)));
+        assert!(!entries.fresh.contains_key(&gateway(2)), "keys expiring first are evicted");
+        assert!(entries.fresh.contains_key(&gateway(3)));
+
+        entries.prune(Instant::now() + Duration::from_secs(25));
+        assert_eq!(entries.fresh.keys().collect::<Vec<_>>(), [&gateway(1)]);
+    }
+
+    #[tokio::test]
+    async fn test_respects_cache_control() {
+        let mut server = mockito::Server::new_async().await;
+        let gateway = GatewayUri::from_str(&server.url()).unwrap();
+        let keys = server
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "application/ohttp-keys")
+            .with_header("cache-control", "no-store")
+            .with_body(KEYS)
+            .expect(2)
+            .create_async()
+            .await;
+        let cache = key_cache(KeyCacheConfig::default());
+        for _ in 0..2 {
+            let res = cache.get(&gateway).await.unwrap();
+            assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+        }
+        keys.assert_async().await;
+
+        let config =
+            KeyCacheConfig { max_ttl: Duration::from_secs(10), ..KeyCacheConfig::default() };
+        server
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "application/ohttp-keys")
+            .with_header("cache-control", "max-age=31536000")
+            .with_body(KEYS)
+            .create_async()
+            .await;
+        let res = key_cache(config).get(&gateway).await.unwrap();
+        assert_eq!(res.headers()[CACHE_CONTROL], "max-age=10", "TTLs are capped");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_keys() {
+        let mut server = mockito::Server::new_async().await;
+        let gateway = GatewayUri::from_str(&server.url()).unwrap();
+        let cache = key_cache(KeyCacheConfig::default());
+
+        let not_found = server.mock("GET", "/.well-known/ohttp-gateway").with_status(404).create();
+        assert!(matches!(cache.get(&gateway).await, Err(Error::BadGateway)));
+        not_found.remove();
+
+        let html = server
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "text/html")
+            .with_body("<html></html>")
+            .create();
+        assert!(matches!(cache.get(&gateway).await, Err(Error::BadGateway)));
+        html.remove();
+
+        server
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "application/ohttp-keys")
+            .with_body(vec![0; MAX_KEYS_SIZE + 1])
+            .create();
+        assert!(matches!(cache.get(&gateway).await, Err(Error::BadGateway)), "keys are small");
+    }
+}
@@ -1,44 +1,103 @@
-use std::net::SocketAddr;
+use std::any::Any;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-pub(crate) use gateway_prober::Prober;
+pub use gateway_prober::TTLConfig;
+pub(crate) use gateway_prober::{Judgement, Prober};
 pub use gateway_uri::GatewayUri;
 use http::uri::Authority;
 use http_body_util::combinators::BoxBody;
-use http_body_util::{BodyExt, Empty, Full};
+use http_body_util::{BodyExt, Empty, Full, LengthLimitError, Limited};
 use hyper::body::{Bytes, Incoming};
 use hyper::header::{
     HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
     ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_LENGTH, CONTENT_TYPE,
 };
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
 use hyper::{Method, Request, Response};
-use hyper_rustls::builderstates::WantsSchemes;
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
-use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, UnixListener};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 use tokio_util::net::Listener;
-use tracing::{error, info, instrument};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, instrument, warn};
 
+mod address_guard;
 pub mod error;
+pub mod gateway_lists;
 #[cfg(not(feature = "_test-util"))]
 mod gateway_prober;
 #[cfg(feature = "_test-util")]
 pub mod gateway_prober;
+pub use gateway_lists::GatewayLists;
+mod gateway_pool;
+pub use gateway_pool::{Balancing, GatewayPoolConfig};
 mod gateway_uri;
+pub mod header_policy;
+pub use header_policy::HeaderPolicy;
+mod key_cache;
+pub use key_cache::{KeyCacheConfig, KEYS_PATH};
+mod limits;
+mod metrics;
+pub use limits::{ConcurrencyLimits, Load};
+pub use metrics::Metrics;
+mod proxy_protocol;
+mod rate_limit;
+pub use rate_limit::{RateLimit, RateLimits};
+mod service;
+pub use service::RelayService;
+mod timeouts;
+pub use timeouts::UpstreamTimeouts;
+#[cfg(feature = "tls")]
+mod tls;
+mod upstream_proxy;
+#[cfg(feature = "tls")]
+pub use tls::{TlsConfig, DEFAULT_TLS_RELOAD_INTERVAL};
+pub use upstream_proxy::{UpstreamProxies, UpstreamProxy};
+
+use crate::address_guard::{AddressGuard, GuardedConnector};
 use crate::error::{BoxError, Error};
+use crate::gateway_lists::Listed;
+use crate::gateway_pool::GatewayPool;
+use crate::key_cache::KeyCache;
 
 #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
 pub mod bootstrap;
+#[cfg(feature = "ws-bootstrap")]
+pub use bootstrap::ws::WebSocketPolicy;
+#[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+pub use bootstrap::TunnelLimits;
 
 pub const DEFAULT_PORT: u16 = 3000;
+/// Default limit for the size of encapsulated requests and responses. BIP 77
+/// messages are padded to a fixed size well below this.
+pub const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+/// Default number of gateways whose probe results are cached.
+pub const DEFAULT_PROBER_CAPACITY: usize = gateway_prober::DEFAULT_CAPACITY;
+/// How long in-flight requests are given to complete once shutdown is requested.
+pub const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a PROXY protocol header may take to arrive after accepting a connection.
+const PROXY_PROTOCOL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before accepting again after the listener failed, e.g.
+/// because the process ran out of file descriptors.
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+/// How long clients are given to complete the TLS handshake, which holds a
+/// connection slot while it runs.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 pub const OHTTP_RELAY_HOST: HeaderValue = HeaderValue::from_static("0.0.0.0");
 pub const EXPECTED_MEDIA_TYPE: HeaderValue = HeaderValue::from_static("message/ohttp-req");
+pub const EXPECTED_RESPONSE_MEDIA_TYPE: HeaderValue = HeaderValue::from_static("message/ohttp-res");
 
 #[instrument]
 pub async fn listen_tcp(
@@ -46,9 +105,8 @@ pub async fn listen_tcp(
     gateway_origin: GatewayUri,
 ) -> Result<tokio::task::JoinHandle<Result<(), BoxError>>, BoxError> {
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = TcpListener::bind(addr).await?;
-    println!("OHTTP relay listening on tcp://{}", addr);
-    ohttp_relay(listener, RelayConfig::new_with_default_client(gateway_origin)).await
+    let relay = RelayConfig::builder(gateway_origin).build().listen_tcp(addr).await?;
+    Ok(relay.into_join_handle())
 }
 
 #[instrument]
@@ -56,9 +114,8 @@ pub async fn listen_socket(
     socket_path: &str,
     gateway_origin: GatewayUri,
 ) -> Result<tokio::task::JoinHandle<Result<(), BoxError>>, BoxError> {
-    let listener = UnixListener::bind(socket_path)?;
-    info!("OHTTP relay listening on socket: {}", socket_path);
-    ohttp_relay(listener, RelayConfig::new_with_default_client(gateway_origin)).await
+    let relay = RelayConfig::builder(gateway_origin).build().listen_socket(socket_path).await?;
+    Ok(relay.into_join_handle())
 }
 
 #[cfg(feature = "_test-util")]
@@ -66,66 +123,517 @@ pub async fn listen_tcp_on_free_port(
     default_gateway: GatewayUri,
     root_store: rustls::RootCertStore,
 ) -> Result<(u16, tokio::task::JoinHandle<Result<(), BoxError>>), BoxError> {
-    let listener = tokio::net::TcpListener::bind("[::]:0").await?;
-    let port = listener.local_addr()?.port();
-    println!("OHTTP relay binding to port {}", listener.local_addr()?);
-    let config = RelayConfig::new(default_gateway, root_store);
-    let handle = ohttp_relay(listener, config).await?;
-    Ok((port, handle))
+    let relay = RelayConfig::builder(default_gateway)
+        .root_store(root_store)
+        .allow_private_gateways(true)
+        .build()
+        .listen_tcp(SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)))
+        .await?;
+    let port = relay.local_addr().expect("TCP relay must have a local address").port();
+    Ok((port, relay.into_join_handle()))
 }
 
+/// Configuration of an OHTTP relay, see [`RelayConfig::builder`].
 #[derive(Debug)]
-struct RelayConfig {
+pub struct RelayConfig {
     default_gateway: GatewayUri,
     client: HttpClient,
+    relay_timeouts: UpstreamTimeouts,
+    max_request_size: usize,
+    max_response_size: usize,
     prober: Prober,
+    prober_snapshot: Option<PathBuf>,
+    gateway_lists: GatewayLists,
+    address_guard: AddressGuard,
+    upstream_proxies: UpstreamProxies,
+    gateway_pool: Option<Arc<GatewayPool>>,
+    key_cache: Option<KeyCache>,
+    rate_limiters: rate_limit::RateLimiters,
+    proxy_protocol: bool,
+    gauges: limits::Gauges,
+    metrics: Metrics,
+    metrics_endpoint: bool,
+    drain_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    bootstrap: bootstrap::BootstrapConfig,
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    tunnels: bootstrap::Tunnels,
 }
 
 impl RelayConfig {
-    fn new_with_default_client(default_gateway: GatewayUri) -> Self {
-        Self::new(default_gateway, HttpClient::default())
+    /// Start configuring a relay which forwards requests to `/` to `default_gateway`.
+    pub fn builder(default_gateway: GatewayUri) -> RelayConfigBuilder {
+        RelayConfigBuilder::new(default_gateway)
+    }
+
+    /// Bind a TCP listener to `addr` and serve the relay on it.
+    pub async fn listen_tcp(self, addr: SocketAddr) -> Result<RelayHandle, BoxError> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        println!("OHTTP relay listening on tcp://{}", local_addr);
+        let mut handle = self.serve(listener).await?;
+        handle.local_addr = Some(local_addr);
+        Ok(handle)
+    }
+
+    /// Bind a unix domain socket at `socket_path` and serve the relay on it.
+    pub async fn listen_socket(
+        self,
+        socket_path: impl AsRef<std::path::Path>,
+    ) -> Result<RelayHandle, BoxError> {
+        let listener = UnixListener::bind(socket_path.as_ref())?;
+        info!("OHTTP relay listening on socket: {}", socket_path.as_ref().display());
+        self.serve(listener).await
     }
 
-    fn new(default_gateway: GatewayUri, into_client: impl Into<HttpClient>) -> Self {
-        let client = into_client.into();
-        let prober = Prober::new_with_client(client.clone());
-        RelayConfig { default_gateway, client, prober }
+    /// Serve the relay on an already bound listener.
+    pub async fn serve<L>(self, listener: L) -> Result<RelayHandle, BoxError>
+    where
+        L: Listener + Unpin + Send + 'static,
+        L::Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let shutdown = CancellationToken::new();
+        let gauges = self.gauges.clone();
+        let task = ohttp_relay(listener, self, shutdown.clone()).await?;
+        Ok(RelayHandle { local_addr: None, shutdown, gauges, task })
+    }
+
+    /// The relay's metrics, for serving them on a separate admin listener with
+    /// [`Metrics::serve`].
+    pub fn metrics(&self) -> Metrics { self.metrics.clone() }
+
+    /// Turn the config into a [`RelayService`] for serving the relay from an
+    /// existing HTTP server.
+    pub async fn into_service(self) -> RelayService {
+        self.prober.assert_opt_in(&self.default_gateway).await;
+        if let Some(pool) = &self.gateway_pool {
+            tokio::spawn(GatewayPool::check_health_until_dropped(Arc::downgrade(pool)));
+        }
+        RelayService::new(Arc::new(self))
     }
 }
 
-#[derive(Debug, Clone)]
-pub(crate) struct HttpClient(
-    hyper_util::client::legacy::Client<HttpsConnector<HttpConnector>, BoxBody<Bytes, hyper::Error>>,
-);
+/// Builder for [`RelayConfig`].
+#[derive(Debug)]
+pub struct RelayConfigBuilder {
+    default_gateway: GatewayUri,
+    root_store: Option<rustls::RootCertStore>,
+    prober_capacity: usize,
+    ttl_config: TTLConfig,
+    prober_snapshot: Option<PathBuf>,
+    gateway_lists: GatewayLists,
+    allow_private_gateways: bool,
+    upstream_proxies: UpstreamProxies,
+    gateway_pool: Option<GatewayPoolConfig>,
+    key_cache: Option<KeyCacheConfig>,
+    relay_timeouts: UpstreamTimeouts,
+    probe_timeouts: UpstreamTimeouts,
+    max_request_size: usize,
+    max_response_size: usize,
+    header_policy: HeaderPolicy,
+    rate_limits: RateLimits,
+    proxy_protocol: bool,
+    concurrency_limits: ConcurrencyLimits,
+    metrics_gateways: Vec<GatewayUri>,
+    metrics_endpoint: bool,
+    drain_timeout: Duration,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConfig>,
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    bootstrap: bootstrap::BootstrapConfig,
+}
+
+impl RelayConfigBuilder {
+    fn new(default_gateway: GatewayUri) -> Self {
+        Self {
+            default_gateway,
+            root_store: None,
+            prober_capacity: DEFAULT_PROBER_CAPACITY,
+            ttl_config: TTLConfig::default(),
+            prober_snapshot: None,
+            gateway_lists: GatewayLists::default(),
+            allow_private_gateways: false,
+            upstream_proxies: UpstreamProxies::default(),
+            gateway_pool: None,
+            key_cache: None,
+            relay_timeouts: UpstreamTimeouts::RELAY,
+            probe_timeouts: UpstreamTimeouts::PROBE,
+            max_request_size: DEFAULT_MAX_BODY_SIZE,
+            max_response_size: DEFAULT_MAX_BODY_SIZE,
+            header_policy: HeaderPolicy::default(),
+            rate_limits: RateLimits::default(),
+            proxy_protocol: false,
+            concurrency_limits: ConcurrencyLimits::default(),
+            metrics_gateways: Vec::new(),
+            metrics_endpoint: false,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            #[cfg(feature = "tls")]
+            tls: None,
+            #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+            bootstrap: bootstrap::BootstrapConfig::default(),
+        }
+    }
+
+    /// Trust anchors for connections to gateways. Defaults to the webpki roots.
+    pub fn root_store(mut self, root_store: rustls::RootCertStore) -> Self {
+        self.root_store = Some(root_store);
+        self
+    }
+
+    /// Maximum number of gateways whose opt-in status is cached at once.
+    pub fn prober_capacity(mut self, capacity: usize) -> Self {
+        self.prober_capacity = capacity;
+        self
+    }
+
+    /// How long the results of probing a gateway are cached.
+    pub fn ttl_config(mut self, ttl_config: TTLConfig) -> Self {
+        self.ttl_config = ttl_config;
+        self
+    }
+
+    /// Keep the results of probing gateways in a file across restarts. They
+    /// are restored when the config is built, and written every minute and
+    /// on shutdown while the relay serves its own listener.
+    pub fn prober_snapshot(mut self, path: impl Into<PathBuf>) -> Self {
+        self.prober_snapshot = Some(path.into());
+        self
+    }
+
+    /// Gateways to relay to without probing them, and gateways to never probe
+    /// or relay to. Keep a clone to reload the lists while the relay runs.
+    pub fn gateway_lists(mut self, lists: GatewayLists) -> Self {
+        self.gateway_lists = lists;
+        self
+    }
+
+    /// Allow probing and relaying to gateways on loopback, private network or
+    /// other non-public addresses, e.g. for local development. Disabled by
+    /// default, except for the default gateway.
+    pub fn allow_private_gateways(mut self, allowed: bool) -> Self {
+        self.allow_private_gateways = allowed;
+        self
+    }
+
+    /// Connect to gateways through upstream proxies, for relaying, probing,
+    /// fetching keys and bootstrap tunnels alike. Connections are direct by
+    /// default.
+    ///
+    /// HTTP and `socks5h` proxies resolve gateway names themselves, so names
+    /// which resolve to private addresses are not refused through them, see
+    /// [`UpstreamProxy`].
+    pub fn upstream_proxies(mut self, proxies: UpstreamProxies) -> Self {
+        self.upstream_proxies = proxies;
+        self
+    }
+
+    /// Relay requests to the default gateway to a pool of gateways sharing
+    /// its key configuration, see [`GatewayPoolConfig`]. Only the default
+    /// gateway is relayed to if the pool has no other members. Bootstrap
+    /// tunnels and cached keys are always those of the default gateway.
+    pub fn gateway_pool(mut self, config: GatewayPoolConfig) -> Self {
+        self.gateway_pool = Some(config);
+        self
+    }
+
+    /// Serve opted-in gateways' keys at [`KEYS_PATH`], fetching and caching
+    /// them on the relay, for clients which can not bootstrap keys through a
+    /// tunnel to the gateway. Disabled by default, since clients must trust
+    /// the relay not to serve them keys of its own.
+    pub fn key_cache(mut self, config: KeyCacheConfig) -> Self {
+        self.key_cache = Some(config);
+        self
+    }
+
+    /// Deadlines for forwarding requests to gateways, see [`UpstreamTimeouts::RELAY`].
+    pub fn relay_timeouts(mut self, timeouts: UpstreamTimeouts) -> Self {
+        self.relay_timeouts = timeouts;
+        self
+    }
+
+    /// Deadlines for probing gateways for opt-in, see [`UpstreamTimeouts::PROBE`].
+    pub fn probe_timeouts(mut self, timeouts: UpstreamTimeouts) -> Self {
+        self.probe_timeouts = timeouts;
+        self
+    }
+
+    /// Maximum size in bytes of an encapsulated request. Larger requests are
+    /// rejected with 413 Payload Too Large without contacting the gateway.
+    pub fn max_request_size(mut self, max: usize) -> Self {
+        self.max_request_size = max;
+        self
+    }
 
-impl std::ops::Deref for HttpClient {
-    type Target = hyper_util::client::legacy::Client<
-        HttpsConnector<HttpConnector>,
-        BoxBody<Bytes, hyper::Error>,
-    >;
-    fn deref(&self) -> &Self::Target { &self.0 }
+    /// Maximum size in bytes of an encapsulated response. Larger responses are
+    /// answered with 502 Bad Gateway.
+    pub fn max_response_size(mut self, max: usize) -> Self {
+        self.max_response_size = max;
+        self
+    }
+
+    /// Headers sent on requests to gateways, see [`header_policy`].
+    pub fn header_policy(mut self, policy: HeaderPolicy) -> Self {
+        self.header_policy = policy;
+        self
+    }
+
+    /// Per-client rate limits, see [`RateLimits`].
+    pub fn rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
+
+    /// Expect every connection to start with a PROXY protocol header, and
+    /// identify clients by the source address it carries instead of the peer
+    /// address. Only enable this behind a proxy which sends the header, since
+    /// otherwise clients can claim any address.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Caps on open connections, in-flight requests and bootstrap tunnels, see
+    /// [`ConcurrencyLimits`].
+    pub fn concurrency_limits(mut self, limits: ConcurrencyLimits) -> Self {
+        self.concurrency_limits = limits;
+        self
+    }
+
+    /// Gateways which are labeled individually in metrics besides the default
+    /// gateway, see [`Metrics`].
+    pub fn metrics_gateways(mut self, gateways: impl IntoIterator<Item = GatewayUri>) -> Self {
+        self.metrics_gateways.extend(gateways);
+        self
+    }
+
+    /// Serve metrics at `GET /metrics` on the relay listener. Disabled by
+    /// default, see [`Metrics::serve`] for serving them on a separate admin
+    /// listener instead.
+    pub fn metrics_endpoint(mut self, enabled: bool) -> Self {
+        self.metrics_endpoint = enabled;
+        self
+    }
+
+    /// How long in-flight requests and bootstrap tunnels are given to complete
+    /// after shutdown is requested before they are cut off.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Terminate TLS on the listener instead of serving plaintext HTTP.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Allow clients to bootstrap gateway keys through HTTP CONNECT. Enabled by default.
+    #[cfg(feature = "connect-bootstrap")]
+    pub fn connect_bootstrap(mut self, enabled: bool) -> Self {
+        self.bootstrap.connect = enabled;
+        self
+    }
+
+    /// Limits on each CONNECT and WebSocket bootstrap tunnel.
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    pub fn tunnel_limits(mut self, limits: TunnelLimits) -> Self {
+        self.bootstrap.tunnel_limits = limits;
+        self
+    }
+
+    /// Allow clients to bootstrap gateway keys through WebSockets. Enabled by default.
+    #[cfg(feature = "ws-bootstrap")]
+    pub fn ws_bootstrap(mut self, enabled: bool) -> Self {
+        self.bootstrap.websocket = enabled;
+        self
+    }
+
+    /// Which origins, subprotocols and frame sizes WebSocket bootstrap
+    /// upgrades are accepted with.
+    #[cfg(feature = "ws-bootstrap")]
+    pub fn websocket_policy(mut self, policy: WebSocketPolicy) -> Self {
+        self.bootstrap.websocket_policy = policy;
+        self
+    }
+
+    /// Finish configuring the relay. Settings are validated when they are
+    /// created, e.g. by [`RateLimit::new`] or [`GatewayLists::new`], so this
+    /// does not fail: an empty gateway pool is ignored, and a prober snapshot
+    /// which can not be read or is corrupt is logged and skipped.
+    ///
+    /// # Panics
+    ///
+    /// If no rustls crypto provider is installed as the process default and
+    /// rustls' enabled features do not determine one.
+    pub fn build(self) -> RelayConfig {
+        let gauges = limits::Gauges::from(self.concurrency_limits);
+        let metrics = Metrics::new(
+            std::iter::once(self.default_gateway.clone()).chain(self.metrics_gateways).collect(),
+        );
+        let header_policy = Arc::new(self.header_policy);
+        let gateway_pool = self.gateway_pool.filter(|pool| !pool.members.is_empty());
+        // pooled gateways are configured by the operator like the default gateway
+        let address_guard = gateway_pool
+            .iter()
+            .flat_map(|pool| pool.members.iter().map(|(gateway, _)| gateway))
+            .fold(
+                AddressGuard::new(&self.default_gateway, self.allow_private_gateways)
+                    .deny_listed(self.gateway_lists.clone()),
+                AddressGuard::trust,
+            );
+        let client = HttpClient::new(
+            self.root_store.clone(),
+            self.relay_timeouts.connect,
+            header_policy.clone(),
+            address_guard.connector(self.upstream_proxies.clone()),
+        );
+        let probe_client = HttpClient::new(
+            self.root_store,
+            self.probe_timeouts.connect,
+            header_policy,
+            address_guard.connector(self.upstream_proxies.clone()),
+        );
+        // keys are fetched with the deadlines of a probe, both are small GETs
+        let gateway_pool = gateway_pool.map(|config| {
+            Arc::new(GatewayPool::new(
+                &self.default_gateway,
+                config,
+                probe_client.clone(),
+                self.probe_timeouts,
+            ))
+        });
+        let key_cache = self
+            .key_cache
+            .map(|config| KeyCache::new(probe_client.clone(), config, self.probe_timeouts));
+        let mut prober = Prober::new(
+            probe_client,
+            self.prober_capacity,
+            self.ttl_config,
+            self.probe_timeouts,
+            metrics.clone(),
+        );
+        if let Some(path) = &self.prober_snapshot {
+            match prober.load_snapshot(path, &self.default_gateway) {
+                Ok(restored) =>
+                    info!("Restored {} gateways from prober snapshot {}", restored, path.display()),
+                Err(e) => warn!("Ignoring prober snapshot {}: {}", path.display(), e),
+            }
+        }
+        RelayConfig {
+            default_gateway: self.default_gateway,
+            client,
+            relay_timeouts: self.relay_timeouts,
+            max_request_size: self.max_request_size,
+            max_response_size: self.max_response_size,
+            prober,
+            prober_snapshot: self.prober_snapshot,
+            gateway_lists: self.gateway_lists,
+            address_guard,
+            upstream_proxies: self.upstream_proxies,
+            gateway_pool,
+            key_cache,
+            rate_limiters: self.rate_limits.into(),
+            proxy_protocol: self.proxy_protocol,
+            gauges: gauges.clone(),
+            metrics: metrics.clone(),
+            metrics_endpoint: self.metrics_endpoint,
+            drain_timeout: self.drain_timeout,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+            bootstrap: self.bootstrap,
+            #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+            tunnels: bootstrap::Tunnels::new(gauges.tunnels, metrics),
+        }
+    }
+}
+
+/// A handle to a running relay.
+///
+/// Awaiting the handle waits for the relay to stop, including draining
+/// connections after a shutdown.
+#[derive(Debug)]
+pub struct RelayHandle {
+    local_addr: Option<SocketAddr>,
+    shutdown: CancellationToken,
+    gauges: limits::Gauges,
+    task: tokio::task::JoinHandle<Result<(), BoxError>>,
+}
+
+impl RelayHandle {
+    /// The address the relay is listening on, if it is listening on TCP.
+    pub fn local_addr(&self) -> Option<SocketAddr> { self.local_addr }
+
+    /// What the relay currently holds open, for monitoring.
+    pub fn load(&self) -> Load { self.gauges.load() }
+
+    /// Stop accepting new connections and drain the open ones, see
+    /// [`RelayConfigBuilder::drain_timeout`].
+    pub fn shutdown(&self) { self.shutdown.cancel() }
+
+    /// A token which shuts down the relay when cancelled, for integrating
+    /// with an application's own shutdown handling.
+    pub fn shutdown_token(&self) -> CancellationToken { self.shutdown.clone() }
+
+    /// The task serving the relay. Awaiting it waits for the relay to stop
+    /// but does not stop it, so take a [`Self::shutdown_token`] first. Once
+    /// shutdown is requested it completes after open connections drained.
+    /// Dropping it leaves the relay running, while aborting it stops the
+    /// relay without draining.
+    pub fn into_join_handle(self) -> tokio::task::JoinHandle<Result<(), BoxError>> { self.task }
 }
 
-impl From<HttpsConnectorBuilder<WantsSchemes>> for HttpClient {
-    fn from(builder: HttpsConnectorBuilder<WantsSchemes>) -> Self {
-        let https = builder.https_or_http().enable_http1().build();
-        Self(Client::builder(TokioExecutor::new()).build(https))
+impl Future for RelayHandle {
+    type Output = Result<(), BoxError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.task).poll(cx).map(|res| res?)
     }
 }
 
-impl Default for HttpClient {
-    fn default() -> Self { HttpsConnectorBuilder::new().with_webpki_roots().into() }
+/// Client for all requests to gateways, which applies the [`HeaderPolicy`].
+#[derive(Debug, Clone)]
+pub(crate) struct HttpClient {
+    client: Client<HttpsConnector<GuardedConnector>, BoxBody<Bytes, hyper::Error>>,
+    header_policy: Arc<HeaderPolicy>,
 }
 
-impl From<rustls::RootCertStore> for HttpClient {
-    fn from(root_store: rustls::RootCertStore) -> Self {
-        HttpsConnectorBuilder::new()
-            .with_tls_config(
+impl HttpClient {
+    pub(crate) fn new(
+        root_store: Option<rustls::RootCertStore>,
+        connect_timeout: Option<Duration>,
+        header_policy: Arc<HeaderPolicy>,
+        mut connector: GuardedConnector,
+    ) -> Self {
+        let builder = match root_store {
+            Some(root_store) => HttpsConnectorBuilder::new().with_tls_config(
                 rustls::ClientConfig::builder()
                     .with_root_certificates(root_store)
                     .with_no_client_auth(),
-            )
-            .into()
+            ),
+            None => HttpsConnectorBuilder::new().with_webpki_roots(),
+        };
+        connector.set_connect_timeout(connect_timeout);
+        let https = builder.https_or_http().enable_http1().wrap_connector(connector);
+        Self { client: Client::builder(TokioExecutor::new()).build(https), header_policy }
+    }
+
+    pub(crate) fn request(
+        &self,
+        mut req: Request<BoxBody<Bytes, hyper::Error>>,
+    ) -> hyper_util::client::legacy::ResponseFuture {
+        self.header_policy.apply(req.headers_mut());
+        self.client.request(req)
+    }
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        let connector = AddressGuard::allow_all().connector(UpstreamProxies::default());
+        Self::new(None, None, Arc::default(), connector)
     }
 }
 
@@ -133,50 +641,217 @@ impl From<rustls::RootCertStore> for HttpClient {
 async fn ohttp_relay<L>(
     mut listener: L,
     config: RelayConfig,
+    shutdown: CancellationToken,
 ) -> Result<tokio::task::JoinHandle<Result<(), BoxError>>, BoxError>
 where
     L: Listener + Unpin + Send + 'static,
     L::Io: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
-    config.prober.assert_opt_in(&config.default_gateway).await;
+    #[cfg(feature = "tls")]
+    let tls_acceptor = config.tls.as_ref().map(|tls| {
+        tokio::spawn(tls.clone().watch(shutdown.clone()));
+        tls.acceptor()
+    });
 
-    let config = Arc::new(config);
+    let service = config.into_service().await;
+    if let Some(path) = service.config.prober_snapshot.clone() {
+        let (config, shutdown) = (service.config.clone(), shutdown.clone());
+        tokio::spawn(async move { config.prober.snapshot_until(&path, shutdown).await });
+    }
 
     let handle = tokio::spawn(async move {
-        while let Ok((stream, _)) = listener.accept().await {
-            let config = config.clone();
-            let io = TokioIo::new(stream);
-            tokio::spawn(async move {
-                if let Err(err) = http1::Builder::new()
-                    .serve_connection(io, service_fn(|req| serve_ohttp_relay(req, &config)))
-                    .with_upgrades()
-                    .await
-                {
-                    error!("Error serving connection: {:?}", err);
+        let mut connections = JoinSet::new();
+        let mut backoff = false;
+        loop {
+            if std::mem::take(&mut backoff) {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => {}
+                }
+            }
+            let (mut stream, peer_addr) = tokio::select! {
+                _ = shutdown.cancelled() => break,
+                Some(_) = connections.join_next() => continue,
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(err) if is_connection_error(&err) => {
+                        debug!("Connection aborted before it was accepted: {:?}", err);
+                        continue;
+                    }
+                    Err(err) => {
+                        // e.g. out of file descriptors, which frees up as
+                        // connections close, so back off rather than spin
+                        error!("Error accepting connection: {:?}", err);
+                        backoff = true;
+                        continue;
+                    }
+                },
+            };
+            let (service, shutdown) = (service.clone(), shutdown.clone());
+            let peer_ip = peer_ip(&peer_addr);
+            let connection = service.config.gauges.connections.try_acquire();
+            #[cfg(feature = "tls")]
+            let tls_acceptor = tls_acceptor.clone();
+            connections.spawn(async move {
+                let client_ip = match service.config.proxy_protocol {
+                    true => {
+                        let header = proxy_protocol::read_source_addr(&mut stream);
+                        match tokio::time::timeout(PROXY_PROTOCOL_TIMEOUT, header).await {
+                            Ok(Ok(source)) => source.map(|source| source.ip()),
+                            Ok(Err(err)) => {
+                                error!("Invalid PROXY protocol header: {}", err);
+                                return;
+                            }
+                            Err(_) => {
+                                error!("Timed out reading PROXY protocol header");
+                                return;
+                            }
+                        }
+                    }
+                    false => peer_ip,
+                };
+                let service = service.with_client_ip(client_ip);
+                #[cfg(feature = "tls")]
+                if let Some(tls_acceptor) = tls_acceptor {
+                    let Ok(_connection) = connection else {
+                        // not worth a handshake just to answer 503
+                        debug!("Closing TLS connection past the connection cap");
+                        return;
+                    };
+                    let handshake = tls_acceptor.accept(stream);
+                    return match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+                        Ok(Ok(stream)) => serve_connection(stream, service, shutdown).await,
+                        Ok(Err(err)) => {
+                            service.config.metrics.record_tls_handshake_failure("failed");
+                            error!("TLS handshake failed: {:?}", err);
+                        }
+                        Err(_) => {
+                            service.config.metrics.record_tls_handshake_failure("timedout");
+                            error!("Timed out waiting for TLS handshake");
+                        }
+                    };
+                }
+                match connection {
+                    Ok(_connection) => serve_connection(stream, service, shutdown).await,
+                    Err(e) => shed_connection(stream, e).await,
                 }
             });
         }
+        shutdown.cancel();
+        drain(connections, &service.config).await;
         Ok(())
     });
 
     Ok(handle)
 }
 
+/// Whether an accept error only concerns the connection being accepted, as
+/// opposed to the listener.
+fn is_connection_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        err.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+/// The IP address of a peer accepted by a TCP listener, as opposed to e.g. a
+/// unix socket peer.
+fn peer_ip(peer_addr: &dyn Any) -> Option<IpAddr> {
+    peer_addr.downcast_ref::<SocketAddr>().map(SocketAddr::ip)
+}
+
+async fn serve_connection<I>(stream: I, service: RelayService, shutdown: CancellationToken)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+    // HTTP/1.1 or HTTP/2, the latter either with prior knowledge or negotiated by ALPN
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let conn = builder.serve_connection_with_upgrades(io, service);
+    tokio::pin!(conn);
+
+    let res = tokio::select! {
+        res = conn.as_mut() => res,
+        _ = shutdown.cancelled() => {
+            // finish any in-flight request, then close the connection
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+
+    if let Err(err) = res {
+        error!("Error serving connection: {:?}", err);
+    }
+}
+
+/// Answer every request on a connection past the connection cap with `error`
+/// and close it, giving up after [`limits::LOAD_SHED_RETRY_AFTER`].
+async fn shed_connection<I>(stream: I, error: Error)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let shed = hyper::service::service_fn(|_| {
+        std::future::ready(Ok::<_, std::convert::Infallible>(error.to_response()))
+    });
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder.http1().keep_alive(false);
+    let conn = builder.serve_connection(TokioIo::new(stream), shed);
+    if let Ok(Err(err)) = tokio::time::timeout(limits::LOAD_SHED_RETRY_AFTER, conn).await {
+        debug!("Error shedding connection: {:?}", err);
+    }
+}
+
+/// Wait for open connections and bootstrap tunnels to finish until the drain
+/// timeout, then cut off whatever remains.
+async fn drain(mut connections: JoinSet<()>, config: &RelayConfig) {
+    let mut drained = 0;
+    let all_closed = async {
+        while connections.join_next().await.is_some() {
+            drained += 1;
+        }
+        #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+        config.tunnels.wait().await;
+    };
+    if tokio::time::timeout(config.drain_timeout, all_closed).await.is_err() {
+        warn!("Drain timeout of {:?} elapsed", config.drain_timeout);
+    }
+
+    let cut_off = connections.len();
+    connections.shutdown().await;
+    #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
+    let tunnels_cut_off = config.tunnels.close().await;
+    #[cfg(not(any(feature = "connect-bootstrap", feature = "ws-bootstrap")))]
+    let tunnels_cut_off = 0;
+
+    info!(
+        "OHTTP relay shut down: drained {} connections, cut off {} connections and {} bootstrap tunnels",
+        drained, cut_off, tunnels_cut_off
+    );
+}
+
 #[instrument]
 async fn serve_ohttp_relay(
     req: Request<Incoming>,
     config: &RelayConfig,
+    client_ip: Option<IpAddr>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let limiters = &config.rate_limiters;
     let mut res = match (req.method(), req.uri().path()) {
         (&Method::OPTIONS, _) => Ok(handle_preflight()),
         (&Method::GET, "/health") => Ok(health_check().await),
-        (&Method::POST, _) => match parse_gateway_uri(&req, config).await {
-            Ok(gateway_uri) => handle_ohttp_relay(req, config, gateway_uri).await,
-            Err(e) => Err(e),
-        },
+        (&Method::GET, "/metrics") if config.metrics_endpoint => Ok(config.metrics.to_response()),
+        (&Method::POST, _) => Ok(handle_post(req, config, client_ip).await),
+        (&Method::GET, path)
+            if config.key_cache.is_some() && key_cache::gateway_path(path).is_some() =>
+            handle_keys(&req, config, client_ip).await,
         #[cfg(any(feature = "connect-bootstrap", feature = "ws-bootstrap"))]
-        (&Method::GET, _) | (&Method::CONNECT, _) => match parse_gateway_uri(&req, config).await {
-            Ok(gateway_uri) => crate::bootstrap::handle_ohttp_keys(req, gateway_uri).await,
+        (&Method::GET, _) | (&Method::CONNECT, _) => match limiters.bootstrap.check(client_ip) {
+            Ok(()) => match parse_gateway_uri(&req, config, client_ip).await {
+                Ok(gateway_uri) =>
+                    crate::bootstrap::handle_ohttp_keys(req, gateway_uri, config).await,
+                Err(e) => Err(e),
+            },
             Err(e) => Err(e),
         },
         _ => Err(Error::NotFound),
@@ -186,32 +861,86 @@ async fn serve_ohttp_relay(
     Ok(res)
 }
 
+/// Relay a POST request and record its outcome in the metrics.
+async fn handle_post(
+    req: Request<Incoming>,
+    config: &RelayConfig,
+    client_ip: Option<IpAddr>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let start = Instant::now();
+    let mut gateway = None;
+    let res = async {
+        config.rate_limiters.relay.check(client_ip)?;
+        let gateway_uri = parse_gateway_uri(&req, config, client_ip).await?;
+        gateway = Some(gateway_uri.clone());
+        handle_ohttp_relay(req, config, gateway_uri).await
+    }
+    .await
+    .unwrap_or_else(|e| e.to_response());
+    config.metrics.record_relayed(gateway.as_ref(), res.status(), start.elapsed());
+    res
+}
+
+/// Serve a gateway's keys from the key cache.
+async fn handle_keys(
+    req: &Request<Incoming>,
+    config: &RelayConfig,
+    client_ip: Option<IpAddr>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
+    let key_cache = config.key_cache.as_ref().ok_or(Error::NotFound)?;
+    config.rate_limiters.bootstrap.check(client_ip)?;
+    let gateway_uri = key_cache::gateway_path(req.uri().path())
+        .and_then(|path| parse_gateway_uri_from_path(path, &config.default_gateway).ok())
+        .ok_or_else(|| Error::BadRequest("Invalid gateway".to_string()))?;
+    let gateway_uri = check_gateway(gateway_uri, config, client_ip).await?;
+    key_cache.get(&gateway_uri).await
+}
+
 async fn parse_gateway_uri(
     req: &Request<Incoming>,
     config: &RelayConfig,
+    client_ip: Option<IpAddr>,
 ) -> Result<GatewayUri, Error> {
     // for POST and GET (websockets), the gateway URI is provided in the path
     // for CONNECT requests, just an authority is provided, and we assume HTTPS
     let gateway_uri = match req.method() {
-        &Method::CONNECT => req.uri().authority().cloned().map(GatewayUri::from),
+        // CONNECT tunnels are restricted to the exact port the gateway opted in on
+        &Method::CONNECT =>
+            req.uri().authority().filter(|a| a.port().is_some()).cloned().map(GatewayUri::from),
         _ => parse_gateway_uri_from_path(req.uri().path(), &config.default_gateway).ok(),
     }
     .ok_or_else(|| Error::BadRequest("Invalid gateway".to_string()))?;
+    check_gateway(gateway_uri, config, client_ip).await
+}
+
+/// Check that the relay may reach `gateway_uri`, because the operator allows
+/// it or because it opted in.
+async fn check_gateway(
+    gateway_uri: GatewayUri,
+    config: &RelayConfig,
+    client_ip: Option<IpAddr>,
+) -> Result<GatewayUri, Error> {
+    match config.gateway_lists.lookup(&gateway_uri) {
+        Some(Listed::Denied) => return Err(Error::Denied),
+        Some(Listed::Allowed) => return Ok(gateway_uri),
+        None => {}
+    }
+
+    if !config.prober.is_known(&gateway_uri).await {
+        config.rate_limiters.probe.check(client_ip)?;
+    }
 
     let policy = match config.prober.check_opt_in(&gateway_uri).await {
         Some(policy) => Ok(policy),
         None => Err(Error::Unavailable(config.prober.unavailable_for().await)),
     }?;
 
-    if policy.bip77_allowed {
-        Ok(gateway_uri)
-    } else {
-        // TODO Cache-Control header for error based on policy.expires
-        // is not found the right error? maybe forbidden or bad gateway?
-        // prober policy judgement can be an enum instead of a bool to
-        // distinguish 4xx vs. 5xx failures, 4xx being an explicit opt out and
-        // 5xx for IO errors etc
-        Err(Error::NotFound)
+    // responses are cacheable until the gateway is probed again
+    let max_age = policy.expires.saturating_duration_since(Instant::now());
+    match policy.judgement {
+        Judgement::OptIn => Ok(gateway_uri),
+        Judgement::OptOut => Err(Error::Forbidden(max_age)),
+        Judgement::Unreachable => Err(Error::GatewayUnavailable(max_age)),
     }
 }
 
@@ -222,7 +951,7 @@ fn parse_gateway_uri_from_path(path: &str, default: &GatewayUri) -> Result<Gatew
 
     let path = &path[1..];
 
-    if "http://" == &path[..7] || "https://" == &path[..8] {
+    if path.starts_with("http://") || path.starts_with("https://") {
         GatewayUri::from_str(path)
     } else {
         Ok(Authority::from_str(path)?.into())
@@ -252,20 +981,29 @@ async fn handle_ohttp_relay(
     config: &RelayConfig,
     gateway: GatewayUri,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, Error> {
-    let fwd_req = into_forward_req(req, gateway)?;
-    forward_request(fwd_req, config).await.map(|res| {
-        let (parts, body) = res.into_parts();
-        let boxed_body = BoxBody::new(body);
-        Response::from_parts(parts, boxed_body)
-    })
+    let body = read_forward_body(req, config.max_request_size).await?;
+    let _in_flight = config.gauges.in_flight_requests.try_acquire()?;
+    let encapsulated_res = match &config.gateway_pool {
+        Some(pool) if gateway == config.default_gateway =>
+            forward_to_pool(pool, body, config).await?,
+        _ => forward_request(forward_req(&gateway, body)?, config).await?,
+    };
+    // Only the encapsulated response is relayed, so no gateway metadata such
+    // as cookies or server headers can reach the client.
+    Response::builder()
+        .status(hyper::StatusCode::OK)
+        .header(CONTENT_TYPE, EXPECTED_RESPONSE_MEDIA_TYPE)
+        .header(CONTENT_LENGTH, encapsulated_res.len())
+        .body(full(encapsulated_res))
+        .map_err(|e| Error::InternalServerError(Box::new(e)))
 }
 
-/// Convert an incoming request into a request to forward to the target gateway server.
+/// Read the body of an incoming request to forward to the target gateway server.
+///
+/// The body is read in full before forwarding, so that requests exceeding
+/// `max_size` are rejected before any bytes reach the gateway.
 #[instrument]
-fn into_forward_req(
-    req: Request<Incoming>,
-    gateway_origin: GatewayUri,
-) -> Result<Request<BoxBody<Bytes, hyper::Error>>, Error> {
+async fn read_forward_body(req: Request<Incoming>, max_size: usize) -> Result<Bytes, Error> {
     let (head, body) = req.into_parts();
 
     if head.method != hyper::Method::POST {
@@ -276,24 +1014,145 @@ fn into_forward_req(
         return Err(Error::UnsupportedMediaType);
     }
 
-    let mut builder = Request::builder()
+    let content_length = head
+        .headers
+        .get(CONTENT_LENGTH)
+        .map(|value| value.to_str().ok().and_then(|value| value.parse::<usize>().ok()))
+        .map(|length| length.ok_or_else(|| Error::BadRequest("Invalid Content-Length".to_string())))
+        .transpose()?;
+    if content_length.is_some_and(|length| length > max_size) {
+        return Err(Error::PayloadTooLarge);
+    }
+
+    // without a Content-Length the body can only be measured by reading it
+    let body = Limited::new(body, max_size).collect().await.map_err(|e| {
+        if e.is::<LengthLimitError>() {
+            Error::PayloadTooLarge
+        } else {
+            Error::BadRequest("Failed to read request body".to_string())
+        }
+    })?;
+    Ok(body.to_bytes())
+}
+
+/// The request forwarding `body` to the target gateway server.
+fn forward_req(
+    gateway_origin: &GatewayUri,
+    body: Bytes,
+) -> Result<Request<BoxBody<Bytes, hyper::Error>>, Error> {
+    Request::builder()
         .method(hyper::Method::POST)
         .uri(gateway_origin.rfc_9540_url())
-        .header(CONTENT_TYPE, EXPECTED_MEDIA_TYPE);
+        .header(CONTENT_TYPE, EXPECTED_MEDIA_TYPE)
+        .header(CONTENT_LENGTH, body.len())
+        .body(full(body))
+        .map_err(|e| Error::InternalServerError(Box::new(e)))
+}
 
-    if let Some(content_length) = head.headers.get(CONTENT_LENGTH) {
-        builder = builder.header(CONTENT_LENGTH, content_length);
+/// Forward a request to a member of the default gateway's pool. Requests
+/// which could not reach a member are sent to another one, while those which
+/// reached a member are never sent twice. Members on the deny list are
+/// skipped, as the default gateway was checked against the lists already.
+async fn forward_to_pool(
+    pool: &GatewayPool,
+    body: Bytes,
+    config: &RelayConfig,
+) -> Result<Bytes, Error> {
+    let mut tried =
+        pool.members_where(|gateway| config.gateway_lists.lookup(gateway) == Some(Listed::Denied));
+    let mut last_error = Error::BadGateway;
+    while let Some(lease) = pool.select(&tried) {
+        match forward_request(forward_req(lease.gateway(), body.clone())?, config).await {
+            Ok(encapsulated_res) => {
+                lease.succeeded();
+                return Ok(encapsulated_res);
+            }
+            Err(ForwardError::Connect(e)) => {
+                warn!("Could not connect to pooled gateway {}", lease.gateway().to_uri());
+                tried.push(lease.index());
+                lease.failed();
+                last_error = e;
+            }
+            Err(ForwardError::Unhealthy(e)) => {
+                lease.failed();
+                return Err(e);
+            }
+            Err(ForwardError::Invalid(e)) => return Err(e),
+        }
     }
+    Err(last_error)
+}
 
-    builder.body(BoxBody::new(body)).map_err(|e| Error::InternalServerError(Box::new(e)))
+/// How forwarding a request to a gateway failed, which tells whether it
+/// counts against the health of a pooled gateway.
+#[derive(Debug)]
+enum ForwardError {
+    /// No connection to the gateway could be made, so the request never
+    /// reached it.
+    Connect(Error),
+    /// The gateway failed or timed out after the request was sent.
+    Unhealthy(Error),
+    /// The gateway answered with something other than an encapsulated
+    /// response.
+    Invalid(Error),
+}
+
+impl From<ForwardError> for Error {
+    fn from(e: ForwardError) -> Self {
+        match e {
+            ForwardError::Connect(e) | ForwardError::Unhealthy(e) | ForwardError::Invalid(e) => e,
+        }
+    }
 }
 
+/// Forward a request to the gateway and read the encapsulated response within
+/// the relay timeouts. Anything but a `200` with a `message/ohttp-res` body is
+/// treated as a bad gateway.
 #[instrument]
 async fn forward_request(
     req: Request<BoxBody<Bytes, hyper::Error>>,
     config: &RelayConfig,
-) -> Result<Response<Incoming>, Error> {
-    config.client.request(req).await.map_err(|_| Error::BadGateway)
+) -> Result<Bytes, ForwardError> {
+    let timeouts = &config.relay_timeouts;
+    let exchange = async {
+        let res = timeouts
+            .first_byte(config.client.request(req))
+            .await
+            .map_err(|_| ForwardError::Unhealthy(Error::GatewayTimeout))?
+            .map_err(|e| {
+                let error = match error::io_error_kind(&e) {
+                    Some(std::io::ErrorKind::TimedOut) => Error::GatewayTimeout,
+                    _ => Error::BadGateway,
+                };
+                match e.is_connect() {
+                    true => ForwardError::Connect(error),
+                    false => ForwardError::Unhealthy(error),
+                }
+            })?;
+        if res.status() != hyper::StatusCode::OK {
+            warn!("Gateway responded with status {}", res.status());
+            return Err(match res.status().is_server_error() {
+                true => ForwardError::Unhealthy(Error::BadGateway),
+                false => ForwardError::Invalid(Error::BadGateway),
+            });
+        }
+        if res.headers().get(CONTENT_TYPE) != Some(&EXPECTED_RESPONSE_MEDIA_TYPE) {
+            warn!("Gateway responded with content type {:?}", res.headers().get(CONTENT_TYPE));
+            return Err(ForwardError::Invalid(Error::BadGateway));
+        }
+        let body = Limited::new(res.into_body(), config.max_response_size)
+            .collect()
+            .await
+            .map_err(|e| {
+                if e.is::<LengthLimitError>() {
+                    warn!("Gateway response exceeded {} bytes", config.max_response_size);
+                    return ForwardError::Invalid(Error::BadGateway);
+                }
+                ForwardError::Unhealthy(Error::BadGateway)
+            })?;
+        Ok(body.to_bytes())
+    };
+    timeouts.total(exchange).await.map_err(|_| ForwardError::Unhealthy(Error::GatewayTimeout))?
 }
 
 pub(crate) fn empty() -> BoxBody<Bytes, hyper::Error> {
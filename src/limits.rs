@@ -0,0 +1,116 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+
+/// How long clients are asked to wait before retrying when the relay sheds load.
+pub(crate) const LOAD_SHED_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Caps on what the relay holds open at once. `None` disables a cap.
+///
+/// Past a cap the relay sheds load by answering with 503 Service Unavailable
+/// and a `Retry-After` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    /// Client connections accepted by the relay's own listener.
+    pub connections: Option<usize>,
+    /// Requests being relayed to gateways.
+    pub in_flight_requests: Option<usize>,
+    /// CONNECT and WebSocket bootstrap tunnels.
+    pub tunnels: Option<usize>,
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> Self {
+        Self { connections: Some(4096), in_flight_requests: Some(1024), tunnels: Some(256) }
+    }
+}
+
+/// What the relay currently holds open, see [`ConcurrencyLimits`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Load {
+    pub connections: usize,
+    pub in_flight_requests: usize,
+    pub tunnels: usize,
+}
+
+/// Counters for each of the [`ConcurrencyLimits`], shared by clones.
+#[derive(Debug, Clone)]
+pub(crate) struct Gauges {
+    pub(crate) connections: Gauge,
+    pub(crate) in_flight_requests: Gauge,
+    pub(crate) tunnels: Gauge,
+}
+
+impl From<ConcurrencyLimits> for Gauges {
+    fn from(limits: ConcurrencyLimits) -> Self {
+        Self {
+            connections: Gauge::new(limits.connections),
+            in_flight_requests: Gauge::new(limits.in_flight_requests),
+            tunnels: Gauge::new(limits.tunnels),
+        }
+    }
+}
+
+impl Gauges {
+    pub(crate) fn load(&self) -> Load {
+        Load {
+            connections: self.connections.current(),
+            in_flight_requests: self.in_flight_requests.current(),
+            tunnels: self.tunnels.current(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Gauge {
+    max: usize,
+    current: Arc<AtomicUsize>,
+}
+
+impl Gauge {
+    fn new(max: Option<usize>) -> Self {
+        Self { max: max.unwrap_or(usize::MAX), current: Arc::default() }
+    }
+
+    /// Count one more, unless the cap is reached. The count is released when
+    /// the guard is dropped.
+    pub(crate) fn try_acquire(&self) -> Result<GaugeGuard, Error> {
+        self.current
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| (n < self.max).then_some(n + 1))
+            .map(|_| GaugeGuard(self.current.clone()))
+            .map_err(|_| Error::Unavailable(LOAD_SHED_RETRY_AFTER))
+    }
+
+    pub(crate) fn current(&self) -> usize { self.current.load(Ordering::Acquire) }
+}
+
+#[derive(Debug)]
+pub(crate) struct GaugeGuard(Arc<AtomicUsize>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) { self.0.fetch_sub(1, Ordering::AcqRel); }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gauge() {
+        let gauge = Gauge::new(Some(2));
+        let first = gauge.try_acquire().expect("below cap");
+        let second = gauge.try_acquire().expect("below cap");
+        assert_eq!(gauge.current(), 2);
+        match gauge.try_acquire() {
+            Err(Error::Unavailable(retry_after)) => assert_eq!(retry_after, LOAD_SHED_RETRY_AFTER),
+            res => panic!("cap should be reached, got {:?}", res),
+        }
+        drop(first);
+        assert_eq!(gauge.current(), 1);
+        let _third = gauge.try_acquire().expect("released count should be reusable");
+        drop(second);
+        assert_eq!(gauge.clone().current(), 1, "clones should share the count");
+    }
+}
@@ -1,6 +1,16 @@
+use std::net::SocketAddr;
 use std::str::FromStr;
+use std::time::Duration;
 
-use ohttp_relay::{GatewayUri, DEFAULT_PORT};
+#[cfg(feature = "tls")]
+use ohttp_relay::TlsConfig;
+use ohttp_relay::{
+    GatewayLists, GatewayPoolConfig, GatewayUri, KeyCacheConfig, RateLimit, RateLimits,
+    RelayConfig, TTLConfig, UpstreamProxies, UpstreamProxy, DEFAULT_PORT, DEFAULT_PROBER_CAPACITY,
+    KEYS_PATH,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
@@ -18,19 +28,242 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let gateway_origin =
         GatewayUri::from_str(&gateway_origin_str).expect("Invalid GATEWAY_ORIGIN URI");
 
-    match (port_env, unix_socket_env) {
+    let prober_capacity = parse_env("PROBER_CAPACITY").unwrap_or(DEFAULT_PROBER_CAPACITY);
+    assert!(prober_capacity > 0, "PROBER_CAPACITY must be at least 1");
+    let ttl_config = ttl_config_from_env();
+    info!("Caching up to {} probe results with {:?}", prober_capacity, ttl_config);
+
+    let mut builder = RelayConfig::builder(gateway_origin)
+        .prober_capacity(prober_capacity)
+        .ttl_config(ttl_config);
+    if let Ok(snapshot_path) = std::env::var("PROBER_SNAPSHOT") {
+        builder = builder.prober_snapshot(snapshot_path);
+    }
+    if let Ok(allow) = std::env::var("ALLOW_PRIVATE_GATEWAYS") {
+        let allow = allow.parse().expect("ALLOW_PRIVATE_GATEWAYS must be true or false");
+        if allow {
+            warn!("Gateways on private addresses may be probed and relayed to");
+        }
+        builder = builder.allow_private_gateways(allow);
+    }
+    builder = builder.upstream_proxies(upstream_proxies_from_env());
+    if let Ok(members) = std::env::var("GATEWAY_POOL") {
+        let mut pool = GatewayPoolConfig::default();
+        for member in members.split(',').map(str::trim).filter(|member| !member.is_empty()) {
+            let (gateway, weight) = match member.rsplit_once('=') {
+                Some((gateway, weight)) =>
+                    (gateway, weight.trim().parse().expect("Invalid GATEWAY_POOL weight")),
+                None => (member, 1),
+            };
+            let gateway =
+                GatewayUri::from_str(gateway.trim()).expect("Invalid GATEWAY_POOL gateway");
+            pool.members.push((gateway, weight));
+        }
+        if let Some(balancing) = parse_env("GATEWAY_POOL_BALANCING") {
+            pool.balancing = balancing;
+        }
+        info!("Pooling the default gateway with {:?}", pool);
+        builder = builder.gateway_pool(pool);
+    }
+    if parse_env("CACHE_OHTTP_KEYS").unwrap_or(false) {
+        let mut key_cache = KeyCacheConfig::default();
+        if let Some(secs) = parse_env("OHTTP_KEYS_DEFAULT_TTL") {
+            key_cache.default_ttl = Duration::from_secs(secs);
+        }
+        if let Some(secs) = parse_env("OHTTP_KEYS_MAX_TTL") {
+            key_cache.max_ttl = Duration::from_secs(secs);
+            assert!(
+                key_cache.max_ttl <= MAX_PROBE_TTL,
+                "OHTTP_KEYS_MAX_TTL must be at most {:?}",
+                MAX_PROBE_TTL
+            );
+        }
+        info!("Serving cached gateway keys at {} with {:?}", KEYS_PATH, key_cache);
+        builder = builder.key_cache(key_cache);
+    }
+    let proxy_protocol = parse_env("PROXY_PROTOCOL").unwrap_or(false);
+    if proxy_protocol {
+        info!("Expecting a PROXY protocol header on every connection");
+    }
+    builder = builder.proxy_protocol(proxy_protocol).rate_limits(rate_limits_from_env());
+    if let Ok(lists_path) = std::env::var("GATEWAY_LISTS") {
+        let lists = GatewayLists::from_file(lists_path).expect("Invalid GATEWAY_LISTS");
+        tokio::spawn(reload_gateway_lists_on_sighup(lists.clone()));
+        builder = builder.gateway_lists(lists);
+    }
+    #[cfg(feature = "tls")]
+    match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(cert_path), Ok(key_path)) => {
+            let tls = TlsConfig::from_pem_files(cert_path, key_path)
+                .expect("Invalid TLS_CERT or TLS_KEY");
+            tokio::spawn(reload_tls_on_sighup(tls.clone()));
+            builder = builder.tls(tls);
+        }
+        (Err(_), Err(_)) => {}
+        _ => panic!("TLS_CERT and TLS_KEY environment variables must be set together."),
+    }
+    #[cfg(not(feature = "tls"))]
+    if std::env::var_os("TLS_CERT").is_some() || std::env::var_os("TLS_KEY").is_some() {
+        panic!("TLS_CERT and TLS_KEY require ohttp-relay to be built with the tls feature.");
+    }
+
+    let config = builder.build();
+    let metrics = config.metrics();
+    let relay = match (port_env, unix_socket_env) {
         (Ok(_), Ok(_)) => panic!(
             "Both PORT and UNIX_SOCKET environment variables are set. Please specify only one."
         ),
-        (Err(_), Ok(unix_socket_path)) =>
-            ohttp_relay::listen_socket(&unix_socket_path, gateway_origin).await?,
+        (Err(_), Ok(unix_socket_path)) => config.listen_socket(&unix_socket_path).await?,
         (Ok(port_str), Err(_)) => {
             let port: u16 = port_str.parse().expect("Invalid PORT");
-            ohttp_relay::listen_tcp(port, gateway_origin).await?
+            config.listen_tcp(SocketAddr::from(([0, 0, 0, 0], port))).await?
+        }
+        (Err(_), Err(_)) =>
+            config.listen_tcp(SocketAddr::from(([0, 0, 0, 0], DEFAULT_PORT))).await?,
+    };
+
+    if let Ok(metrics_addr) = std::env::var("METRICS_ADDR") {
+        let metrics_addr: SocketAddr = metrics_addr.parse().expect("Invalid METRICS_ADDR");
+        let listener = tokio::net::TcpListener::bind(metrics_addr).await?;
+        info!("Metrics listening on http://{}/metrics", metrics_addr);
+        metrics.serve(listener, relay.shutdown_token());
+    }
+
+    let shutdown = relay.shutdown_token();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Shutdown signal received, draining connections");
+        shutdown.cancel();
+    });
+
+    relay.await
+}
+
+/// The longest TTL accepted from the environment, which keeps expiry times far
+/// from overflowing.
+const MAX_PROBE_TTL: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Parse an environment variable, if it is set.
+fn parse_env<T: FromStr>(name: &str) -> Option<T> {
+    let value = std::env::var(name).ok()?;
+    Some(value.parse().unwrap_or_else(|_| panic!("Invalid {}: {}", name, value)))
+}
+
+/// The default TTLs, overridden by `PROBE_TTL_*` environment variables in
+/// seconds.
+fn ttl_config_from_env() -> TTLConfig {
+    let mut ttl_config = TTLConfig::default();
+    for (name, ttl) in [
+        ("PROBE_TTL_OPT_IN", &mut ttl_config.opt_in),
+        ("PROBE_TTL_HTTP_2XX", &mut ttl_config.http_2xx),
+        ("PROBE_TTL_HTTP_4XX", &mut ttl_config.http_4xx),
+        ("PROBE_TTL_HTTP_504", &mut ttl_config.http_504_gateway_timeout),
+        ("PROBE_TTL_HTTP_5XX", &mut ttl_config.http_5xx),
+        ("PROBE_TTL_DNS", &mut ttl_config.dns),
+        ("PROBE_TTL_RESET_BY_PEER", &mut ttl_config.reset_by_peer),
+        ("PROBE_TTL_TIMEDOUT", &mut ttl_config.timedout),
+        ("PROBE_TTL_DEFAULT", &mut ttl_config.default),
+        ("PROBE_TTL_HEADER_MIN", &mut ttl_config.header_min),
+        ("PROBE_TTL_HEADER_MAX", &mut ttl_config.header_max),
+    ] {
+        if let Some(secs) = parse_env(name) {
+            *ttl = Duration::from_secs(secs);
+            assert!(*ttl <= MAX_PROBE_TTL, "{} must be at most {:?}", name, MAX_PROBE_TTL);
+        }
+    }
+    assert!(
+        ttl_config.header_min <= ttl_config.header_max,
+        "PROBE_TTL_HEADER_MIN must not exceed PROBE_TTL_HEADER_MAX"
+    );
+    ttl_config
+}
+
+/// Per-client rate limits from `RATE_LIMIT_{RELAY,PROBE,BOOTSTRAP}_BURST` and
+/// the matching `_PER_SECOND` refill rates, which must be set together, and
+/// `RATE_LIMIT_MAX_CLIENTS`.
+fn rate_limits_from_env() -> RateLimits {
+    let mut rate_limits = RateLimits::default();
+    for (name, limit) in [
+        ("RATE_LIMIT_RELAY", &mut rate_limits.relay),
+        ("RATE_LIMIT_PROBE", &mut rate_limits.probe),
+        ("RATE_LIMIT_BOOTSTRAP", &mut rate_limits.bootstrap),
+    ] {
+        let burst = parse_env(&format!("{}_BURST", name));
+        let per_second = parse_env(&format!("{}_PER_SECOND", name));
+        *limit = match (burst, per_second) {
+            (Some(burst), Some(per_second)) => Some(
+                RateLimit::new(burst, per_second)
+                    .unwrap_or_else(|e| panic!("Invalid {}: {}", name, e)),
+            ),
+            (None, None) => None,
+            _ => panic!("{0}_BURST and {0}_PER_SECOND must be set together.", name),
+        };
+    }
+    if let Some(max_clients) = parse_env("RATE_LIMIT_MAX_CLIENTS") {
+        rate_limits.max_clients = max_clients;
+        assert!(max_clients > 0, "RATE_LIMIT_MAX_CLIENTS must be at least 1");
+    }
+    if rate_limits.relay.is_some() || rate_limits.probe.is_some() || rate_limits.bootstrap.is_some()
+    {
+        info!("Rate limiting clients with {:?}", rate_limits);
+    }
+    rate_limits
+}
+
+/// The proxy in `UPSTREAM_PROXY`, used for every gateway unless overridden by
+/// `UPSTREAM_PROXY_ROUTES`, a comma separated list of `<pattern>=<proxy>` or
+/// `<pattern>=direct`, e.g. `*.onion=socks5h://127.0.0.1:9050,payjo.in=direct`.
+fn upstream_proxies_from_env() -> UpstreamProxies {
+    let mut proxies = UpstreamProxies::new(
+        std::env::var("UPSTREAM_PROXY")
+            .ok()
+            .map(|proxy| proxy.parse::<UpstreamProxy>().expect("Invalid UPSTREAM_PROXY")),
+    );
+    if let Ok(routes) = std::env::var("UPSTREAM_PROXY_ROUTES") {
+        for route in routes.split(',').map(str::trim).filter(|route| !route.is_empty()) {
+            let (pattern, proxy) = route
+                .split_once('=')
+                .unwrap_or_else(|| panic!("Invalid UPSTREAM_PROXY_ROUTES entry: {}", route));
+            let proxy = match proxy.trim() {
+                "direct" => None,
+                proxy => Some(proxy.parse().expect("Invalid UPSTREAM_PROXY_ROUTES proxy")),
+            };
+            proxies = proxies.route(pattern.trim(), proxy).expect("Invalid UPSTREAM_PROXY_ROUTES");
+        }
+    }
+    info!("Connecting to gateways with {:?}", proxies);
+    proxies
+}
+
+/// Wait for SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to install SIGINT handler");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
+    }
+}
+
+/// Reload the TLS certificate and key from disk whenever SIGHUP is received.
+#[cfg(feature = "tls")]
+async fn reload_tls_on_sighup(tls: TlsConfig) {
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+    while sighup.recv().await.is_some() {
+        if let Err(e) = tls.reload() {
+            error!("Failed to reload TLS certificate: {}", e);
+        }
+    }
+}
+
+/// Reload the gateway allow and deny lists from disk whenever SIGHUP is received.
+async fn reload_gateway_lists_on_sighup(lists: GatewayLists) {
+    let mut sighup = signal(SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+    while sighup.recv().await.is_some() {
+        if let Err(e) = lists.reload() {
+            error!("Failed to reload gateway lists: {}", e);
         }
-        (Err(_), Err(_)) => ohttp_relay::listen_tcp(DEFAULT_PORT, gateway_origin).await?,
     }
-    .await?
 }
 
 fn init_tracing() {
@@ -0,0 +1,273 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::error;
+
+use crate::error::Error;
+use crate::full;
+use crate::gateway_uri::GatewayUri;
+
+/// Label for gateways which are not labeled individually.
+const OTHER_GATEWAY: &str = "other";
+
+/// Latency buckets in seconds. BIP 77 mailboxes long poll for up to 30
+/// seconds, so the buckets extend well beyond that.
+const RELAY_DURATION_BUCKETS: &[f64] =
+    &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 30.0, 45.0, 60.0, 90.0];
+
+/// Outcomes of looking up a gateway in the prober cache.
+pub(crate) const CACHE_HIT: &str = "hit";
+pub(crate) const CACHE_MISS: &str = "miss";
+pub(crate) const CACHE_IN_FLIGHT_JOIN: &str = "in_flight_join";
+pub(crate) const CACHE_CAPACITY_REJECTION: &str = "capacity_rejection";
+
+/// Prometheus metrics of a relay, see [`RelayConfig::metrics`](crate::RelayConfig::metrics).
+///
+/// Only the default gateway and the gateways passed to
+/// [`RelayConfigBuilder::metrics_gateways`](crate::RelayConfigBuilder::metrics_gateways)
+/// are labeled individually, all others are counted as `other`. Labeling
+/// arbitrary gateways would let anyone who can read the metrics tell when a
+/// rarely used gateway sees a request, and would grow without bound.
+#[derive(Debug, Clone)]
+pub struct Metrics(Arc<Inner>);
+
+#[derive(Debug)]
+struct Inner {
+    registry: Registry,
+    labeled_gateways: HashSet<GatewayUri>,
+    relayed_requests: IntCounterVec,
+    relay_duration: HistogramVec,
+    prober_cache: IntCounterVec,
+    probes: IntCounterVec,
+    tunnels_active: IntGaugeVec,
+    tunnel_bytes: IntCounterVec,
+    #[cfg(feature = "tls")]
+    tls_handshake_failures: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self { Self::new(HashSet::new()) }
+}
+
+impl Metrics {
+    pub(crate) fn new(labeled_gateways: HashSet<GatewayUri>) -> Self {
+        let registry = Registry::new();
+        let relayed_requests = IntCounterVec::new(
+            Opts::new("ohttp_relay_requests_total", "Relayed OHTTP requests"),
+            &["gateway", "outcome", "status"],
+        )
+        .expect("metric options should be valid");
+        let relay_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "ohttp_relay_request_duration_seconds",
+                "Time to respond to relayed OHTTP requests",
+            )
+            .buckets(RELAY_DURATION_BUCKETS.to_vec()),
+            &["gateway", "outcome"],
+        )
+        .expect("metric options should be valid");
+        let prober_cache = IntCounterVec::new(
+            Opts::new("ohttp_relay_prober_cache_total", "Lookups of gateways' opt-in status"),
+            &["result"],
+        )
+        .expect("metric options should be valid");
+        let probes = IntCounterVec::new(
+            Opts::new("ohttp_relay_probes_total", "Gateway probe results by TTL category"),
+            &["category"],
+        )
+        .expect("metric options should be valid");
+        let tunnels_active = IntGaugeVec::new(
+            Opts::new("ohttp_relay_tunnels_active", "Open bootstrap tunnels"),
+            &["kind"],
+        )
+        .expect("metric options should be valid");
+        let tunnel_bytes = IntCounterVec::new(
+            Opts::new("ohttp_relay_tunnel_bytes_total", "Bytes relayed by bootstrap tunnels"),
+            &["kind", "direction"],
+        )
+        .expect("metric options should be valid");
+        #[cfg(feature = "tls")]
+        let tls_handshake_failures = IntCounterVec::new(
+            Opts::new(
+                "ohttp_relay_tls_handshake_failures_total",
+                "TLS handshakes with clients which failed or timed out",
+            ),
+            &["reason"],
+        )
+        .expect("metric options should be valid");
+
+        for collector in [
+            Box::new(relayed_requests.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(relay_duration.clone()),
+            Box::new(prober_cache.clone()),
+            Box::new(probes.clone()),
+            Box::new(tunnels_active.clone()),
+            Box::new(tunnel_bytes.clone()),
+        ] {
+            registry.register(collector).expect("metrics should only be registered once");
+        }
+        #[cfg(feature = "tls")]
+        registry
+            .register(Box::new(tls_handshake_failures.clone()))
+            .expect("metrics should only be registered once");
+
+        Self(Arc::new(Inner {
+            registry,
+            labeled_gateways,
+            relayed_requests,
+            relay_duration,
+            prober_cache,
+            probes,
+            tunnels_active,
+            tunnel_bytes,
+            #[cfg(feature = "tls")]
+            tls_handshake_failures,
+        }))
+    }
+
+    /// Encode all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.0.registry.gather(), &mut buf)
+            .expect("encoding to a vec should not fail");
+        String::from_utf8(buf).expect("text format should be UTF-8")
+    }
+
+    /// Serve `GET /metrics` on a separate admin listener until `shutdown` is
+    /// cancelled.
+    pub fn serve(self, listener: TcpListener, shutdown: CancellationToken) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let stream = tokio::select! {
+                    _ = shutdown.cancelled() => return,
+                    accepted = listener.accept() => match accepted {
+                        Ok((stream, _)) => stream,
+                        Err(err) => {
+                            error!("Error accepting metrics connection: {:?}", err);
+                            return;
+                        }
+                    },
+                };
+                let metrics = self.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(|req: Request<Incoming>| {
+                        let res = match (req.method(), req.uri().path()) {
+                            (&Method::GET, "/metrics") => metrics.to_response(),
+                            _ => Error::NotFound.to_response(),
+                        };
+                        std::future::ready(Ok::<_, std::convert::Infallible>(res))
+                    });
+                    if let Err(err) =
+                        http1::Builder::new().serve_connection(TokioIo::new(stream), service).await
+                    {
+                        error!("Error serving metrics connection: {:?}", err);
+                    }
+                });
+            }
+        })
+    }
+
+    pub(crate) fn to_response(&self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let mut res = Response::new(full(self.encode()));
+        res.headers_mut().insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        );
+        res
+    }
+
+    pub(crate) fn record_relayed(
+        &self,
+        gateway: Option<&GatewayUri>,
+        status: StatusCode,
+        elapsed: Duration,
+    ) {
+        let gateway = match gateway {
+            Some(gateway) if self.0.labeled_gateways.contains(gateway) =>
+                gateway.to_uri().to_string(),
+            _ => OTHER_GATEWAY.to_string(),
+        };
+        let outcome = match status {
+            StatusCode::OK => "relayed",
+            status if status.is_client_error() => "rejected",
+            _ => "failed",
+        };
+        self.0
+            .relayed_requests
+            .with_label_values(&[gateway.as_str(), outcome, status.as_str()])
+            .inc();
+        self.0
+            .relay_duration
+            .with_label_values(&[gateway.as_str(), outcome])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub(crate) fn record_prober_cache(&self, result: &str) {
+        self.0.prober_cache.with_label_values(&[result]).inc();
+    }
+
+    pub(crate) fn record_probe(&self, category: &str) {
+        self.0.probes.with_label_values(&[category]).inc();
+    }
+
+    pub(crate) fn tunnel_opened(&self, kind: &str) {
+        self.0.tunnels_active.with_label_values(&[kind]).inc();
+    }
+
+    pub(crate) fn tunnel_closed(&self, kind: &str, to_gateway: u64, to_client: u64) {
+        self.0.tunnels_active.with_label_values(&[kind]).dec();
+        self.0.tunnel_bytes.with_label_values(&[kind, "to_gateway"]).inc_by(to_gateway);
+        self.0.tunnel_bytes.with_label_values(&[kind, "to_client"]).inc_by(to_client);
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn record_tls_handshake_failure(&self, reason: &str) {
+        self.0.tls_handshake_failures.with_label_values(&[reason]).inc();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn test_gateway_labels_are_bounded() {
+        let labeled = GatewayUri::from_str("https://payjo.in").unwrap();
+        let metrics = Metrics::new(HashSet::from([labeled.clone()]));
+        metrics.record_relayed(Some(&labeled), StatusCode::OK, Duration::from_millis(10));
+        for i in 0..10 {
+            let gateway = GatewayUri::from_str(&format!("https://gateway{}.example", i)).unwrap();
+            metrics.record_relayed(Some(&gateway), StatusCode::BAD_GATEWAY, Duration::ZERO);
+        }
+        metrics.record_relayed(None, StatusCode::TOO_MANY_REQUESTS, Duration::ZERO);
+
+        let encoded = metrics.encode();
+        assert!(encoded.contains(
+            r#"ohttp_relay_requests_total{gateway="https://payjo.in:443/",outcome="relayed",status="200"} 1"#
+        ));
+        assert!(encoded.contains(
+            r#"ohttp_relay_requests_total{gateway="other",outcome="failed",status="502"} 10"#
+        ));
+        assert!(encoded.contains(
+            r#"ohttp_relay_requests_total{gateway="other",outcome="rejected",status="429"} 1"#
+        ));
+        assert!(!encoded.contains("gateway0.example"), "unlabeled gateways should not appear");
+    }
+}
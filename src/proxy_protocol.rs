@@ -0,0 +1,155 @@
+//! Reading the client address from a [PROXY protocol] header, which reverse
+//! proxies send at the start of a connection.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::error::BoxError;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible version 1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+/// Read a version 1 or 2 PROXY protocol header from the start of `io`,
+/// consuming exactly the header. Returns the source address, or `None` if the
+/// proxy does not know it, e.g. for health checks.
+pub(crate) async fn read_source_addr<R>(io: &mut R) -> Result<Option<SocketAddr>, BoxError>
+where
+    R: AsyncRead + Unpin,
+{
+    // both versions are at least this long
+    let mut prefix = [0u8; 12];
+    io.read_exact(&mut prefix).await?;
+    if &prefix == V2_SIGNATURE {
+        read_v2(io).await
+    } else if prefix.starts_with(b"PROXY ") {
+        read_v1(io, prefix).await
+    } else {
+        Err("Missing PROXY protocol header".into())
+    }
+}
+
+async fn read_v1<R>(io: &mut R, prefix: [u8; 12]) -> Result<Option<SocketAddr>, BoxError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err("PROXY protocol header too long".into());
+        }
+        line.push(io.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") => {
+            let source: IpAddr = fields.next().ok_or("Missing source address")?.parse()?;
+            let _destination: IpAddr =
+                fields.next().ok_or("Missing destination address")?.parse()?;
+            let port: u16 = fields.next().ok_or("Missing source port")?.parse()?;
+            Ok(Some(SocketAddr::new(source, port)))
+        }
+        _ => Err(format!("Invalid PROXY protocol header: {}", line).into()),
+    }
+}
+
+async fn read_v2<R>(io: &mut R) -> Result<Option<SocketAddr>, BoxError>
+where
+    R: AsyncRead + Unpin,
+{
+    let version_command = io.read_u8().await?;
+    let family = io.read_u8().await?;
+    let len = io.read_u16().await?;
+    let mut addresses = vec![0u8; len as usize];
+    io.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err("Unsupported PROXY protocol version".into());
+    }
+    match version_command & 0x0f {
+        // LOCAL, the connection was made by the proxy itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err("Unsupported PROXY protocol command".into()),
+    }
+
+    let source = match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip: [u8; 4] = addresses[0..4].try_into().expect("slice has length 4");
+            let port = u16::from_be_bytes([addresses[8], addresses[9]]);
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip: [u8; 16] = addresses[0..16].try_into().expect("slice has length 16");
+            let port = u16::from_be_bytes([addresses[32], addresses[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        }
+        0x1 | 0x2 => return Err("Truncated PROXY protocol addresses".into()),
+        // UNSPEC or UNIX
+        _ => None,
+    };
+    Ok(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_v1() {
+        let mut io: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /";
+        let source = read_source_addr(&mut io).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(io, b"GET /", "only the header should be consumed");
+
+        let mut io: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        let source = read_source_addr(&mut io).await.unwrap();
+        assert_eq!(source, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let mut io: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_source_addr(&mut io).await.unwrap(), None);
+
+        let mut io: &[u8] = b"PROXY TCP4 not-an-ip 198.51.100.1 56324 443\r\n";
+        assert!(read_source_addr(&mut io).await.is_err());
+
+        let mut io: &[u8] = &[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat();
+        assert!(read_source_addr(&mut io).await.is_err(), "header length should be bounded");
+    }
+
+    #[tokio::test]
+    async fn test_v2() {
+        let header = [
+            V2_SIGNATURE.as_slice(),
+            &[0x21, 0x11, 0x00, 0x0c],
+            &[192, 0, 2, 1],
+            &[198, 51, 100, 1],
+            &56324u16.to_be_bytes(),
+            &443u16.to_be_bytes(),
+            b"GET /",
+        ]
+        .concat();
+        let mut io = header.as_slice();
+        let source = read_source_addr(&mut io).await.unwrap();
+        assert_eq!(source, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(io, b"GET /", "only the header should be consumed");
+
+        let local = [V2_SIGNATURE.as_slice(), &[0x20, 0x00, 0x00, 0x00]].concat();
+        assert_eq!(read_source_addr(&mut local.as_slice()).await.unwrap(), None);
+
+        let truncated =
+            [V2_SIGNATURE.as_slice(), &[0x21, 0x11, 0x00, 0x04], &[192, 0, 2, 1]].concat();
+        assert!(read_source_addr(&mut truncated.as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_missing_header() {
+        let mut io: &[u8] = b"POST / HTTP/1.1\r\n";
+        assert!(read_source_addr(&mut io).await.is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::error::{BoxError, Error};
+
+/// Default maximum number of clients whose request rate is tracked at once.
+pub const DEFAULT_MAX_CLIENTS: usize = 10_000;
+
+/// Stands in for the time a bucket which never refills is full at.
+const NEVER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// A token bucket which allows bursts of `burst` requests and refills at
+/// `per_second` requests per second.
+///
+/// Prefer [`RateLimit::new`], which rejects limits that could never admit a
+/// request. Such limits reject every request rather than panic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per_second: f64,
+}
+
+impl RateLimit {
+    /// A limit which allows bursts of `burst` requests and refills at
+    /// `per_second` requests per second, both of which must be positive.
+    pub fn new(burst: u32, per_second: f64) -> Result<Self, BoxError> {
+        if burst == 0 {
+            return Err("rate limit burst must be at least 1".into());
+        }
+        if !per_second.is_finite() || per_second <= 0.0 {
+            return Err(format!("rate limit must be a positive rate, got {}", per_second).into());
+        }
+        Ok(Self { burst, per_second })
+    }
+}
+
+/// Limits on the request rate of each client, keyed by its IP address, or
+/// for IPv6 its /64 prefix. `None` disables a limit.
+///
+/// All limits are disabled by default, because behind a reverse proxy every
+/// client shares the proxy's address unless the PROXY protocol is enabled
+/// with [`RelayConfigBuilder::proxy_protocol`](crate::RelayConfigBuilder::proxy_protocol).
+/// Clients without an IP address, such as peers on a Unix socket, are never
+/// limited.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Relayed OHTTP requests.
+    pub relay: Option<RateLimit>,
+    /// Requests for gateways whose opt-in is not yet known and must be probed.
+    pub probe: Option<RateLimit>,
+    /// CONNECT and WebSocket bootstrap tunnels and requests for cached keys.
+    pub bootstrap: Option<RateLimit>,
+    /// Maximum number of clients tracked per limit. Clients which are idle
+    /// long enough to have refilled their bucket are forgotten first, if none
+    /// are, new clients are rejected until one is.
+    pub max_clients: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self { relay: None, probe: None, bootstrap: None, max_clients: DEFAULT_MAX_CLIENTS }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiters {
+    pub(crate) relay: RateLimiter,
+    pub(crate) probe: RateLimiter,
+    pub(crate) bootstrap: RateLimiter,
+}
+
+impl From<RateLimits> for RateLimiters {
+    fn from(limits: RateLimits) -> Self {
+        Self {
+            relay: RateLimiter::new(limits.relay, limits.max_clients),
+            probe: RateLimiter::new(limits.probe, limits.max_clients),
+            bootstrap: RateLimiter::new(limits.bootstrap, limits.max_clients),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: Option<RateLimit>,
+    max_clients: usize,
+    state: Mutex<Buckets>,
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    by_client: HashMap<IpAddr, Bucket>,
+    /// Clients ordered by when their buckets will have refilled, so that
+    /// idle clients are forgotten without scanning every bucket.
+    by_full_at: BTreeSet<(Instant, IpAddr)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    full_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_second).min(limit.burst as f64);
+        self.updated = now;
+    }
+
+    /// How long until `tokens` are available, [`Duration::MAX`] if never.
+    fn wait_for(&self, limit: &RateLimit, tokens: f64) -> Duration {
+        Duration::try_from_secs_f64(((tokens - self.tokens) / limit.per_second).max(0.0))
+            .unwrap_or(Duration::MAX)
+    }
+
+    /// When the bucket will have refilled, if ever.
+    fn full_at(&self, limit: &RateLimit) -> Instant {
+        let wait = self.wait_for(limit, limit.burst as f64);
+        self.updated.checked_add(wait).unwrap_or_else(|| self.updated + NEVER)
+    }
+}
+
+impl RateLimiter {
+    fn new(limit: Option<RateLimit>, max_clients: usize) -> Self {
+        Self { limit, max_clients, state: Mutex::default() }
+    }
+
+    /// Take a token from the client's bucket, or fail with the time until one
+    /// becomes available.
+    pub(crate) fn check(&self, client: Option<IpAddr>) -> Result<(), Error> {
+        let (Some(limit), Some(client)) = (&self.limit, client) else {
+            return Ok(());
+        };
+        let client = client_key(client);
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("lock should not be poisoned");
+        state.forget_full(now);
+
+        if !state.by_client.contains_key(&client) && state.by_client.len() >= self.max_clients {
+            let retry_after = state
+                .by_full_at
+                .first()
+                .map(|(full_at, _)| full_at.saturating_duration_since(now))
+                .unwrap_or(Duration::ZERO);
+            return Err(Error::TooManyRequests(retry_after));
+        }
+
+        let Buckets { by_client, by_full_at } = &mut *state;
+        let bucket = by_client.entry(client).or_insert(Bucket {
+            tokens: limit.burst as f64,
+            updated: now,
+            full_at: now,
+        });
+        by_full_at.remove(&(bucket.full_at, client));
+        bucket.refill(limit, now);
+        let res = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Error::TooManyRequests(bucket.wait_for(limit, 1.0)))
+        };
+        bucket.full_at = bucket.full_at(limit);
+        by_full_at.insert((bucket.full_at, client));
+        res
+    }
+}
+
+impl Buckets {
+    /// Forget clients whose buckets have refilled, which is indistinguishable
+    /// from never having seen them.
+    fn forget_full(&mut self, now: Instant) {
+        while let Some(&(full_at, client)) = self.by_full_at.first() {
+            if full_at > now {
+                break;
+            }
+            self.by_full_at.pop_first();
+            self.by_client.remove(&client);
+        }
+    }
+}
+
+/// IPv6 clients can usually choose any address in their /64, so they are
+/// limited as a whole.
+fn client_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const LIMIT: RateLimit = RateLimit { burst: 2, per_second: 1.0 };
+
+    fn client(last_octet: u8) -> Option<IpAddr> {
+        Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, last_octet)))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_token_bucket() {
+        let limiter = RateLimiter::new(Some(LIMIT), DEFAULT_MAX_CLIENTS);
+        assert!(limiter.check(client(1)).is_ok());
+        assert!(limiter.check(client(1)).is_ok());
+        match limiter.check(client(1)) {
+            Err(Error::TooManyRequests(retry_after)) =>
+                assert_eq!(retry_after, Duration::from_secs(1)),
+            res => panic!("burst should be exhausted, got {:?}", res),
+        }
+        assert!(limiter.check(client(2)).is_ok(), "clients should be limited separately");
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check(client(1)).is_ok(), "bucket should refill");
+        assert!(limiter.check(client(1)).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unlimited() {
+        let limiter = RateLimiter::new(None, DEFAULT_MAX_CLIENTS);
+        for _ in 0..10 {
+            assert!(limiter.check(client(1)).is_ok());
+        }
+        let limiter = RateLimiter::new(Some(LIMIT), DEFAULT_MAX_CLIENTS);
+        for _ in 0..10 {
+            assert!(limiter.check(None).is_ok(), "clients without address are not limited");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_bounded_clients() {
+        let limiter = RateLimiter::new(Some(LIMIT), 2);
+        assert!(limiter.check(client(1)).is_ok());
+        assert!(limiter.check(client(1)).is_ok());
+        assert!(limiter.check(client(2)).is_ok());
+        match limiter.check(client(3)) {
+            Err(Error::TooManyRequests(retry_after)) =>
+                assert_eq!(retry_after, Duration::from_secs(1)),
+            res => panic!("new clients should be rejected when full, got {:?}", res),
+        }
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(limiter.check(client(3)).is_ok(), "idle clients should be forgotten");
+        let state = limiter.state.lock().unwrap();
+        assert!(state.by_client.contains_key(&client(1).unwrap()), "client 1 has not refilled");
+        assert!(!state.by_client.contains_key(&client(2).unwrap()));
+        assert_eq!(state.by_full_at.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_zero_rate() {
+        assert!(RateLimit::new(1, 0.0).is_err());
+        assert!(RateLimit::new(1, f64::NAN).is_err());
+        assert!(RateLimit::new(1, f64::INFINITY).is_err());
+        assert!(RateLimit::new(0, 1.0).is_err());
+        assert_eq!(RateLimit::new(2, 0.5).unwrap(), RateLimit { burst: 2, per_second: 0.5 });
+
+        // limits built without `new` must not panic either
+        let limiter = RateLimiter::new(Some(RateLimit { burst: 1, per_second: 0.0 }), 1);
+        assert!(limiter.check(client(1)).is_ok());
+        match limiter.check(client(1)) {
+            Err(Error::TooManyRequests(retry_after)) => assert_eq!(retry_after, Duration::MAX),
+            res => panic!("bucket should never refill, got {:?}", res),
+        }
+        tokio::time::advance(Duration::from_secs(60)).await;
+        assert!(limiter.check(client(2)).is_err(), "table should stay full");
+        let res = Error::TooManyRequests(Duration::MAX).to_response();
+        assert_eq!(res.headers()[hyper::header::RETRY_AFTER], u64::MAX.to_string());
+    }
+
+    #[test]
+    fn test_ipv6_prefix_key() {
+        let a = IpAddr::V6("2001:db8::1".parse().unwrap());
+        let b = IpAddr::V6("2001:db8::ffff:1".parse().unwrap());
+        let c = IpAddr::V6("2001:db8:0:1::1".parse().unwrap());
+        assert_eq!(client_key(a), client_key(b));
+        assert_ne!(client_key(a), client_key(c));
+        assert_eq!(
+            client_key(IpAddr::V6("::ffff:192.0.2.1".parse().unwrap())),
+            IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use http::uri::PathAndQuery;
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::service::Service;
+use hyper::{Method, Request, Response};
+
+use crate::error::Error;
+use crate::{serve_ohttp_relay, Load, RelayConfig};
+
+/// The relay as a hyper [`Service`], for mounting it in an existing HTTP
+/// server instead of letting the relay own the listener.
+///
+/// Clones share the same configuration, HTTP client and gateway prober.
+#[derive(Debug, Clone)]
+pub struct RelayService {
+    pub(crate) config: Arc<RelayConfig>,
+    path_prefix: Option<Arc<str>>,
+    client_ip: Option<IpAddr>,
+}
+
+impl RelayService {
+    pub(crate) fn new(config: Arc<RelayConfig>) -> Self {
+        Self { config, path_prefix: None, client_ip: None }
+    }
+
+    /// Only serve requests whose path starts with `prefix`, which is stripped
+    /// before routing, e.g. with the prefix `/relay` a request to
+    /// `/relay/https://payjo.in` is relayed to `https://payjo.in`. Other
+    /// requests are answered with 404 Not Found.
+    pub fn with_path_prefix(mut self, prefix: &str) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        self.path_prefix = match prefix.is_empty() {
+            true => None,
+            false if prefix.starts_with('/') => Some(prefix.into()),
+            false => Some(format!("/{}", prefix).into()),
+        };
+        self
+    }
+
+    /// The address of the client on whose connection requests are served,
+    /// which [`RateLimits`](crate::RateLimits) are applied to. Clients without
+    /// an address are not rate limited.
+    pub fn with_client_ip(mut self, client_ip: Option<IpAddr>) -> Self {
+        self.client_ip = client_ip;
+        self
+    }
+
+    /// What the relay currently holds open, for monitoring.
+    pub fn load(&self) -> Load { self.config.gauges.load() }
+
+    fn strip_path_prefix(&self, mut req: Request<Incoming>) -> Result<Request<Incoming>, Error> {
+        let Some(prefix) = &self.path_prefix else {
+            return Ok(req);
+        };
+
+        // CONNECT requests only name an authority and have no path to mount
+        if req.method() == Method::CONNECT {
+            return Ok(req);
+        }
+
+        let path_and_query = req.uri().path_and_query().map(PathAndQuery::as_str).unwrap_or("/");
+        let stripped = strip_path_prefix(path_and_query, prefix).ok_or(Error::NotFound)?;
+
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query =
+            Some(PathAndQuery::from_str(&stripped).map_err(|_| Error::NotFound)?);
+        *req.uri_mut() = Uri::from_parts(parts).map_err(|_| Error::NotFound)?;
+        Ok(req)
+    }
+}
+
+impl Service<Request<Incoming>> for RelayService {
+    type Response = Response<BoxBody<Bytes, hyper::Error>>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn call(&self, req: Request<Incoming>) -> Self::Future {
+        let service = self.clone();
+        Box::pin(async move {
+            match service.strip_path_prefix(req) {
+                Ok(req) => serve_ohttp_relay(req, &service.config, service.client_ip).await,
+                Err(e) => Ok(e.to_response()),
+            }
+        })
+    }
+}
+
+/// Remove `prefix` from the start of `path_and_query` if it is followed by a
+/// path segment boundary.
+fn strip_path_prefix(path_and_query: &str, prefix: &str) -> Option<String> {
+    let rest = path_and_query.strip_prefix(prefix)?;
+    if rest.is_empty() || rest.starts_with('?') {
+        Some(format!("/{}", rest))
+    } else if rest.starts_with('/') {
+        Some(rest.to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_path_prefix() {
+        assert_eq!(strip_path_prefix("/relay", "/relay").as_deref(), Some("/"));
+        assert_eq!(strip_path_prefix("/relay/", "/relay").as_deref(), Some("/"));
+        assert_eq!(strip_path_prefix("/relay/health", "/relay").as_deref(), Some("/health"));
+        assert_eq!(
+            strip_path_prefix("/relay/https://payjo.in", "/relay").as_deref(),
+            Some("/https://payjo.in"),
+            "gateway URIs in the path should be preserved"
+        );
+        assert_eq!(
+            strip_path_prefix("/relay?foo", "/relay").as_deref(),
+            Some("/?foo"),
+            "query should be preserved"
+        );
+        assert_eq!(
+            strip_path_prefix("/relayed", "/relay"),
+            None,
+            "prefix must end on a path segment boundary"
+        );
+        assert_eq!(strip_path_prefix("/health", "/relay"), None, "prefix must be present");
+    }
+}
@@ -0,0 +1,51 @@
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::error::Elapsed;
+
+/// Deadlines for requests the relay makes to gateways. `None` disables a
+/// deadline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpstreamTimeouts {
+    /// Establishing the TCP connection to the gateway.
+    pub connect: Option<Duration>,
+    /// From sending the request until the response head is received.
+    pub first_byte: Option<Duration>,
+    /// The whole exchange, including reading the response body.
+    pub total: Option<Duration>,
+}
+
+impl UpstreamTimeouts {
+    /// Defaults for relayed requests. BIP 77 mailboxes long poll for up to 30
+    /// seconds before responding, so time to first byte must exceed that.
+    pub const RELAY: Self = Self {
+        connect: Some(Duration::from_secs(10)),
+        first_byte: Some(Duration::from_secs(60)),
+        total: Some(Duration::from_secs(90)),
+    };
+
+    /// Defaults for probing gateways for opt-in.
+    pub const PROBE: Self = Self {
+        connect: Some(Duration::from_secs(5)),
+        first_byte: Some(Duration::from_secs(10)),
+        total: Some(Duration::from_secs(15)),
+    };
+
+    /// No deadlines at all.
+    pub const NONE: Self = Self { connect: None, first_byte: None, total: None };
+
+    pub(crate) async fn first_byte<F: Future>(&self, fut: F) -> Result<F::Output, Elapsed> {
+        with_timeout(self.first_byte, fut).await
+    }
+
+    pub(crate) async fn total<F: Future>(&self, fut: F) -> Result<F::Output, Elapsed> {
+        with_timeout(self.total, fut).await
+    }
+}
+
+async fn with_timeout<F: Future>(duration: Option<Duration>, fut: F) -> Result<F::Output, Elapsed> {
+    match duration {
+        Some(duration) => tokio::time::timeout(duration, fut).await,
+        None => Ok(fut.await),
+    }
+}
@@ -0,0 +1,123 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
+
+use crate::error::BoxError;
+
+/// How often the certificate and key files are checked for changes.
+pub const DEFAULT_TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(30);
+
+/// TLS termination for the relay listener, using a certificate chain and
+/// private key loaded from PEM files.
+///
+/// Reloading only affects new handshakes, so existing connections are kept.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    resolver: Arc<ReloadableCert>,
+    reload_interval: Duration,
+}
+
+impl TlsConfig {
+    /// Load a PEM encoded certificate chain and private key. Fails if either
+    /// file can not be read or the key does not match a supported algorithm.
+    pub fn from_pem_files(
+        cert_path: impl Into<PathBuf>,
+        key_path: impl Into<PathBuf>,
+    ) -> Result<Self, BoxError> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let certified_key = load_certified_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            resolver: Arc::new(ReloadableCert(RwLock::new(Arc::new(certified_key)))),
+            reload_interval: DEFAULT_TLS_RELOAD_INTERVAL,
+        })
+    }
+
+    /// How often to check the certificate and key files for changes.
+    pub fn reload_interval(mut self, interval: Duration) -> Self {
+        self.reload_interval = interval;
+        self
+    }
+
+    /// Reload the certificate chain and private key from disk, e.g. on SIGHUP.
+    /// On error the previously loaded certificate remains in use.
+    pub fn reload(&self) -> Result<(), BoxError> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.resolver.0.write().expect("lock should not be poisoned") = Arc::new(certified_key);
+        info!("Reloaded TLS certificate from {}", self.cert_path.display());
+        Ok(())
+    }
+
+    pub(crate) fn acceptor(&self) -> TlsAcceptor {
+        let mut server_config =
+            ServerConfig::builder().with_no_client_auth().with_cert_resolver(self.resolver.clone());
+        server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(server_config))
+    }
+
+    /// Reload the certificate whenever the files' modification times change,
+    /// until `shutdown` is cancelled.
+    pub(crate) async fn watch(self, shutdown: CancellationToken) {
+        let mut last_modified = self.modified();
+        let mut interval = tokio::time::interval(self.reload_interval);
+        interval.tick().await;
+        loop {
+            tokio::select! {
+                _ = shutdown.cancelled() => return,
+                _ = interval.tick() => {}
+            }
+
+            let modified = self.modified();
+            if modified != last_modified {
+                last_modified = modified;
+                if let Err(e) = self.reload() {
+                    error!("Failed to reload TLS certificate: {}", e);
+                }
+            }
+        }
+    }
+
+    fn modified(&self) -> (Option<SystemTime>, Option<SystemTime>) {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        (modified(&self.cert_path), modified(&self.key_path))
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> Result<CertifiedKey, BoxError> {
+    let cert_pem = std::fs::read(cert_path)
+        .map_err(|e| format!("Failed to read {}: {}", cert_path.display(), e))?;
+    let certs = CertificateDer::pem_slice_iter(&cert_pem).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", cert_path.display()).into());
+    }
+
+    let key_pem = std::fs::read(key_path)
+        .map_err(|e| format!("Failed to read {}: {}", key_path.display(), e))?;
+    let key = PrivateKeyDer::from_pem_slice(&key_pem)?;
+    let signing_key = rustls::crypto::ring::sign::any_supported_type(&key)?;
+
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+/// Resolves to whichever certificate was loaded most recently.
+#[derive(Debug)]
+struct ReloadableCert(RwLock<Arc<CertifiedKey>>);
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().expect("lock should not be poisoned").clone())
+    }
+}
@@ -11,7 +11,7 @@ mod integration {
     use http_body_util::combinators::BoxBody;
     use http_body_util::{BodyExt, Full};
     use hyper::body::{Bytes, Incoming};
-    use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+    use hyper::header::{HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE};
     use hyper::server::conn::http1;
     use hyper::service::service_fn;
     use hyper::{Request, Response};
@@ -36,6 +36,7 @@ mod integration {
         });
     }
 
+    const OHTTP_KEYS: &str = "01002031e1f05a740102115220e9af918f738674aec95f54db6e04eb705aae8e79815500080001000100010003";
     const ENCAPSULATED_REQ: &str = "010020000100014b28f881333e7c164ffc499ad9796f877f4e1051ee6d31bad19dec96c208b4726374e469135906992e1268c594d2a10c695d858c40a026e7965e7d86b83dd440b2c0185204b4d63525";
     const ENCAPSULATED_RES: &str =
         "c789e7151fcba46158ca84b04464910d86f9013e404feea014e7be4a441f234f857fbd";
@@ -117,6 +118,1160 @@ mod integration {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_relay_config_builder() {
+        init_crypto_provider();
+        // the default gateway is never contacted by a health check
+        let gateway = GatewayUri::from_str("http://0.0.0.0:1").unwrap();
+
+        let relay = RelayConfig::builder(gateway)
+            .prober_capacity(1)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+
+        let res = reqwest::get(format!("http://{}/health", relay_addr)).await.unwrap();
+        assert_eq!(res.status(), 200);
+
+        relay.shutdown();
+        tokio::time::timeout(std::time::Duration::from_secs(5), relay)
+            .await
+            .expect("relay should stop after shutdown")
+            .expect("relay should stop without error");
+    }
+
+    #[tokio::test]
+    async fn test_relay_service_path_prefix() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+
+        let service = RelayConfig::builder(gateway.clone())
+            .build()
+            .into_service()
+            .await
+            .with_path_prefix("/relay/");
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = listener.local_addr().unwrap();
+        let server_task = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service.clone();
+                tokio::spawn(async move {
+                    let _ =
+                        http1::Builder::new().serve_connection(TokioIo::new(stream), service).await;
+                });
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let res = client.get(format!("http://{}/relay/health", relay_addr)).send().await.unwrap();
+        assert_eq!(res.status(), 200, "health check should be served under the prefix");
+        let res = client.get(format!("http://{}/health", relay_addr)).send().await.unwrap();
+        assert_eq!(res.status(), 404, "requests outside of the prefix should not be served");
+
+        for gw_path in ["/relay", "/relay/", &format!("/relay/{}", gateway.to_uri())] {
+            let res = client
+                .post(format!("http://{}{}", relay_addr, gw_path))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200, "{} should be relayed", gw_path);
+            assert_eq!(
+                res.bytes().await.unwrap().as_ref(),
+                Vec::from_hex(ENCAPSULATED_RES).unwrap()
+            );
+        }
+
+        server_task.abort();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_request_response_http2() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+
+        let relay = RelayConfig::builder(gateway.clone())
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // HTTP/2 with prior knowledge, multiplexing requests over one connection
+        let stream = TcpStream::connect(relay_addr).await.unwrap();
+        let (sender, conn) =
+            hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+                .await
+                .expect("relay should accept HTTP/2 with prior knowledge");
+        tokio::spawn(conn);
+
+        for gw_path in ["", &gateway.to_uri().to_string()] {
+            let mut req = Request::new(full(Vec::from_hex(ENCAPSULATED_REQ).unwrap()));
+            *req.method_mut() = hyper::Method::POST;
+            *req.uri_mut() = format!("http://{}/{}", relay_addr, gw_path).parse().unwrap();
+            req.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("message/ohttp-req"));
+
+            let res = sender.clone().send_request(req).await.unwrap();
+            assert_eq!(res.version(), hyper::Version::HTTP_2);
+            assert_eq!(res.status(), hyper::StatusCode::OK);
+            assert_eq!(
+                res.headers().get(CONTENT_TYPE),
+                Some(&HeaderValue::from_static("message/ohttp-res"))
+            );
+            let body = res.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body.as_ref(), Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        }
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_tls_termination_and_reload() {
+        init_crypto_provider();
+        let gateway = GatewayUri::from_str("http://0.0.0.0:1").unwrap();
+        let cert_file = NamedTempFile::new().unwrap();
+        let key_file = NamedTempFile::new().unwrap();
+        let write_cert = |cert: &Certificate| {
+            std::fs::write(cert_file.path(), cert.serialize_pem().unwrap()).unwrap();
+            std::fs::write(key_file.path(), cert.serialize_private_key_pem()).unwrap();
+        };
+        let https_client = |cert: CertificateDer<'static>| {
+            reqwest::Client::builder()
+                .no_proxy()
+                .use_rustls_tls()
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(reqwest::Certificate::from_der(cert.as_ref()).unwrap())
+                .build()
+                .unwrap()
+        };
+
+        let old_cert = gen_localhost_cert();
+        let old_cert_der = cert_to_cert_der(&old_cert);
+        write_cert(&old_cert);
+        let tls = TlsConfig::from_pem_files(cert_file.path(), key_file.path())
+            .expect("TLS config should load from PEM files");
+        assert!(
+            TlsConfig::from_pem_files(cert_file.path(), cert_file.path()).is_err(),
+            "a certificate is not a valid private key"
+        );
+
+        let relay = RelayConfig::builder(gateway)
+            .tls(tls.clone())
+            .build()
+            .listen_tcp(SocketAddr::from(([0, 0, 0, 0], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let health_url = format!("https://0.0.0.0:{}/health", relay.local_addr().unwrap().port());
+
+        let old_client = https_client(old_cert_der.clone());
+        let res = old_client.get(&health_url).send().await.expect("TLS should be terminated");
+        assert_eq!(res.status(), 200);
+        assert!(
+            reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .unwrap()
+                .get(health_url.replace("https", "http"))
+                .send()
+                .await
+                .map_or(true, |res| res.status() != 200),
+            "plaintext HTTP should not be served"
+        );
+
+        let new_cert = gen_localhost_cert();
+        write_cert(&new_cert);
+        tls.reload().expect("reloading TLS config should succeed");
+
+        let res = https_client(cert_to_cert_der(&new_cert)).get(&health_url).send().await;
+        assert_eq!(res.expect("new certificate should be served").status(), 200);
+        assert!(
+            https_client(old_cert_der).get(&health_url).send().await.is_err(),
+            "old certificate should no longer be served to new connections"
+        );
+        let res = old_client.get(&health_url).send().await;
+        assert_eq!(res.expect("existing connections should be kept after reload").status(), 200);
+
+        relay.shutdown();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_tls_handshake_timeout() {
+        use tokio::io::AsyncReadExt;
+
+        init_crypto_provider();
+        let cert = gen_localhost_cert();
+        let cert_file = NamedTempFile::new().unwrap();
+        let key_file = NamedTempFile::new().unwrap();
+        std::fs::write(cert_file.path(), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(key_file.path(), cert.serialize_private_key_pem()).unwrap();
+        let config = RelayConfig::builder(GatewayUri::from_str("http://0.0.0.0:1").unwrap())
+            .tls(TlsConfig::from_pem_files(cert_file.path(), key_file.path()).unwrap())
+            .build();
+        let metrics = config.metrics();
+        let relay = config
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+
+        // a client which never starts the handshake is disconnected
+        let mut stalled = TcpStream::connect(relay.local_addr().unwrap()).await.unwrap();
+        let mut buf = [0; 1];
+        let read = stalled.read(&mut buf);
+        let read = tokio::time::timeout(std::time::Duration::from_secs(60), read)
+            .await
+            .expect("the relay should close the connection");
+        assert!(matches!(read, Ok(0) | Err(_)));
+        assert!(metrics
+            .encode()
+            .contains(r#"ohttp_relay_tls_handshake_failures_total{reason="timedout"} 1"#));
+
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_tls_connections_shed_before_handshake() {
+        use tokio::io::AsyncReadExt;
+
+        init_crypto_provider();
+        let cert = gen_localhost_cert();
+        let cert_file = NamedTempFile::new().unwrap();
+        let key_file = NamedTempFile::new().unwrap();
+        std::fs::write(cert_file.path(), cert.serialize_pem().unwrap()).unwrap();
+        std::fs::write(key_file.path(), cert.serialize_private_key_pem()).unwrap();
+        let relay = RelayConfig::builder(GatewayUri::from_str("http://0.0.0.0:1").unwrap())
+            .tls(TlsConfig::from_pem_files(cert_file.path(), key_file.path()).unwrap())
+            .concurrency_limits(ConcurrencyLimits { connections: Some(1), ..Default::default() })
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().unwrap();
+
+        // holds the only connection slot while its handshake is pending
+        let _handshaking = TcpStream::connect(relay_addr).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(relay.load().connections, 1);
+
+        let mut shed = TcpStream::connect(relay_addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(std::time::Duration::from_secs(2), shed.read(&mut buf))
+            .await
+            .expect("connections past the cap should be closed without a handshake");
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_relay_timeout() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_slow(gateway_port, std::time::Duration::from_secs(5)).await;
+        });
+
+        let relay = RelayConfig::builder(gateway)
+            .relay_timeouts(UpstreamTimeouts {
+                first_byte: Some(std::time::Duration::from_millis(200)),
+                ..UpstreamTimeouts::RELAY
+            })
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let res = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .post(format!("http://{}/", relay_addr))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 504, "slow gateway should result in a gateway timeout");
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_body_size_limits() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        init_crypto_provider();
+        let gateway_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let gateway =
+            GatewayUri::from_str(&format!("http://{}", gateway_listener.local_addr().unwrap()))
+                .unwrap();
+        let relay = RelayConfig::builder(gateway)
+            .max_request_size(16)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+
+        let res = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .post(format!("http://{}/", relay_addr))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 413, "oversized Content-Length should be rejected");
+
+        let mut stream = TcpStream::connect(relay_addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST / HTTP/1.1\r\nHost: relay\r\nContent-Type: message/ohttp-req\r\n\
+                  Transfer-Encoding: chunked\r\nConnection: close\r\n\r\n\
+                  10\r\n0123456789abcdef\r\n10\r\n0123456789abcdef\r\n0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 413"), "oversized chunked body should be rejected");
+
+        let accepted =
+            tokio::time::timeout(std::time::Duration::from_millis(100), gateway_listener.accept())
+                .await;
+        assert!(accepted.is_err(), "oversized requests should never reach the gateway");
+        relay.shutdown();
+
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+        let relay = RelayConfig::builder(gateway)
+            .max_response_size(16)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let res = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .post(format!("http://{}/", relay_addr))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 502, "oversized gateway response should be rejected");
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_gateway_response_validation() {
+        init_crypto_provider();
+        for (status, content_type, expected) in [
+            (200, "message/ohttp-res", 200),
+            (200, "text/html", 502),
+            (404, "message/ohttp-res", 502),
+            (500, "text/plain", 502),
+        ] {
+            let gateway_port = find_free_port();
+            let gateway =
+                GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+            let gateway_task = tokio::spawn(async move {
+                let _ = example_gateway_leaky(gateway_port, status, content_type).await;
+            });
+            let relay = RelayConfig::builder(gateway)
+                .build()
+                .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .expect("Failed to listen on free port");
+            let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+            let res = reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .unwrap()
+                .post(format!("http://{}/", relay_addr))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), expected, "gateway responded {} {}", status, content_type);
+            assert!(res.headers().get("set-cookie").is_none(), "cookies should not be relayed");
+            assert!(res.headers().get("server").is_none(), "server should not be relayed");
+            if expected == 200 {
+                assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), "message/ohttp-res");
+                assert_eq!(res.bytes().await.unwrap(), Vec::from_hex(ENCAPSULATED_RES).unwrap());
+            } else {
+                assert!(res.bytes().await.unwrap().is_empty(), "errors should be uniform");
+            }
+
+            relay.shutdown();
+            gateway_task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_opt_out_and_unreachable_gateways() {
+        init_crypto_provider();
+        let opted_out_port = find_free_port();
+        let opted_out_task = tokio::spawn(async move {
+            let _ = example_gateway_leaky(opted_out_port, 404, "text/plain").await;
+        });
+        let default_gateway =
+            GatewayUri::from_str(&format!("http://127.0.0.1:{}", find_free_port())).unwrap();
+        let relay = RelayConfig::builder(default_gateway)
+            .allow_private_gateways(true)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let relay_to = |gateway: String| {
+            client
+                .post(format!("http://{}/{}", relay_addr, gateway))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+        };
+        let max_age = |res: &reqwest::Response| {
+            let cache_control = res.headers().get("cache-control").unwrap().to_str().unwrap();
+            cache_control.strip_prefix("max-age=").unwrap().parse::<u64>().unwrap()
+        };
+
+        let res = relay_to(format!("http://127.0.0.1:{}", opted_out_port)).await.unwrap();
+        assert_eq!(res.status(), 403, "gateway which answers 404 has opted out");
+        assert!(max_age(&res) > 0, "opt-out should be cacheable until the next probe");
+        assert!(res.headers().get("retry-after").is_none(), "opt-out is not worth retrying");
+
+        let res = relay_to(format!("http://127.0.0.1:{}", find_free_port())).await.unwrap();
+        assert_eq!(res.status(), 503, "gateway nobody listens on is unreachable");
+        let retry_after = res.headers().get("retry-after").unwrap().to_str().unwrap();
+        assert_eq!(retry_after.parse::<u64>().unwrap(), max_age(&res));
+
+        relay.shutdown();
+        opted_out_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_key_cache() {
+        init_crypto_provider();
+        let mut gateway = mockito::Server::new_async().await;
+        gateway
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .match_query(mockito::Matcher::Regex("^allowed_purposes$".into()))
+            .with_header("content-type", ALLOWED_PURPOSES_CONTENT_TYPE)
+            .with_body([b"\x00\x01\x2a", MAGIC_BIP77_PURPOSE].concat())
+            .create_async()
+            .await;
+        let keys = gateway
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .match_query(mockito::Matcher::Missing)
+            .with_header("content-type", "application/ohttp-keys")
+            .with_header("cache-control", "max-age=300")
+            .with_header("content-digest", "sha-256=:digest:")
+            .with_body(Vec::from_hex(OHTTP_KEYS).unwrap())
+            .expect(1)
+            .create_async()
+            .await;
+        let mut opted_out = mockito::Server::new_async().await;
+        opted_out.mock("GET", mockito::Matcher::Any).with_status(404).create_async().await;
+        let default_gateway = GatewayUri::from_str(&gateway.url()).unwrap();
+        let relay = RelayConfig::builder(default_gateway)
+            .allow_private_gateways(true)
+            .key_cache(KeyCacheConfig::default())
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        for path in [KEYS_PATH.to_string(), format!("{}/{}", KEYS_PATH, gateway.url())] {
+            let res = client.get(format!("http://{}{}", relay_addr, path)).send().await.unwrap();
+            assert_eq!(res.status(), 200, "keys should be served at {}", path);
+            assert_eq!(res.headers()["content-type"], "application/ohttp-keys");
+            assert_eq!(res.headers()["content-digest"], "sha-256=:digest:");
+            assert_eq!(res.bytes().await.unwrap(), Vec::from_hex(OHTTP_KEYS).unwrap());
+        }
+        keys.assert_async().await;
+
+        let res = client
+            .get(format!("http://{}{}/{}", relay_addr, KEYS_PATH, opted_out.url()))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 403, "keys are only served for opted-in gateways");
+
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_gateway_lists() {
+        init_crypto_provider();
+        // answers probes without opting in, so it is only relayed to if allowed
+        let gateway_port = find_free_port();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_leaky(gateway_port, 200, "message/ohttp-res").await;
+        });
+        let gateway = format!("http://127.0.0.1:{}", gateway_port);
+        let lists = GatewayLists::new(["127.0.0.1"], []).unwrap();
+        let relay = RelayConfig::builder(GatewayUri::from_str(&gateway).unwrap())
+            .gateway_lists(lists.clone())
+            .allow_private_gateways(true)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let relay_to = |gateway: &str| {
+            client
+                .post(format!("http://{}/{}", relay_addr, gateway))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+        };
+        let other_gateway = format!("http://localhost:{}", gateway_port);
+        assert_eq!(relay_to(&gateway).await.unwrap().status(), 200, "allowed without probing");
+        assert_eq!(relay_to(&other_gateway).await.unwrap().status(), 403, "not opted in");
+
+        lists.replace([], ["127.0.0.0/8"]).unwrap();
+        let res = relay_to("").await.unwrap();
+        assert_eq!(res.status(), 403, "denied gateways should not be relayed to");
+        assert_eq!(res.headers().get("cache-control").unwrap(), "no-store");
+        assert_eq!(res.text().await.unwrap(), "Gateway denied by relay policy");
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_private_gateways() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+        // the default gateway is trusted, other ports on its host are not
+        let gateway = format!("http://127.0.0.1:{}", gateway_port);
+        let relay = RelayConfig::builder(GatewayUri::from_str(&gateway).unwrap())
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let relay_to = |gateway: &str| {
+            client
+                .post(format!("http://{}/{}", relay_addr, gateway))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+        };
+        assert_eq!(relay_to(&gateway).await.unwrap().status(), 200);
+        for private in [
+            format!("http://127.0.0.1:{}", find_free_port()),
+            format!("http://localhost:{}", gateway_port),
+            "http://169.254.169.254".to_string(),
+        ] {
+            let res = relay_to(&private).await.unwrap();
+            assert_eq!(res.status(), 403, "{} should not be probed or relayed to", private);
+        }
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_upstream_proxy() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+        // an HTTP CONNECT proxy which reports the targets it tunnels to
+        let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy_listener.local_addr().unwrap();
+        let (targets_tx, mut targets) = tokio::sync::mpsc::unbounded_channel();
+        let proxy_task = tokio::spawn(async move {
+            loop {
+                let (mut client, _) = proxy_listener.accept().await.unwrap();
+                let targets_tx = targets_tx.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(client.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    let target = head.split(' ').nth(1).unwrap().to_string();
+                    let mut gateway = TcpStream::connect(&target).await.unwrap();
+                    targets_tx.send(target).unwrap();
+                    client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut gateway).await;
+                });
+            }
+        });
+        let gateway = format!("http://127.0.0.1:{}", gateway_port);
+        let proxy = UpstreamProxy::from_str(&format!("http://{}", proxy_addr)).unwrap();
+        let relay = RelayConfig::builder(GatewayUri::from_str(&gateway).unwrap())
+            .upstream_proxies(UpstreamProxies::new(Some(proxy)))
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let relay_to = |gateway: &str| {
+            client
+                .post(format!("http://{}/{}", relay_addr, gateway))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+        };
+        let res = relay_to(&gateway).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.bytes().await.unwrap(), Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        assert_eq!(targets.recv().await.unwrap(), format!("127.0.0.1:{}", gateway_port));
+
+        let private = format!("http://127.0.0.1:{}", find_free_port());
+        let res = relay_to(&private).await.unwrap();
+        assert_eq!(res.status(), 403, "private addresses are refused before reaching the proxy");
+        assert!(targets.try_recv().is_err());
+
+        relay.shutdown();
+        proxy_task.abort();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_gateway_pool() {
+        init_crypto_provider();
+        let mut member = mockito::Server::new_async().await;
+        member
+            .mock("GET", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "application/ohttp-keys")
+            .with_body(Vec::from_hex(OHTTP_KEYS).unwrap())
+            .create_async()
+            .await;
+        let relayed = member
+            .mock("POST", "/.well-known/ohttp-gateway")
+            .with_header("content-type", "message/ohttp-res")
+            .with_body(Vec::from_hex(ENCAPSULATED_RES).unwrap())
+            .expect(2)
+            .create_async()
+            .await;
+        // the default gateway answers the first health check and then goes
+        // down, so requests to it fail over to the member serving its keys
+        let default_port = find_free_port();
+        let listener = TcpListener::bind(("127.0.0.1", default_port)).await.unwrap();
+        let default_task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let keys = service_fn(|_| async {
+                let mut res = Response::new(full(Vec::from_hex(OHTTP_KEYS).unwrap()));
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/ohttp-keys"));
+                res.headers_mut().insert(CONNECTION, HeaderValue::from_static("close"));
+                Ok::<_, hyper::Error>(res)
+            });
+            let _ = http1::Builder::new().serve_connection(TokioIo::new(stream), keys).await;
+        });
+        let default_gateway = format!("http://127.0.0.1:{}", default_port);
+        let pool = GatewayPoolConfig {
+            members: vec![(GatewayUri::from_str(&member.url()).unwrap(), 1)],
+            ..GatewayPoolConfig::default()
+        };
+        let relay = RelayConfig::builder(GatewayUri::from_str(&default_gateway).unwrap())
+            .gateway_pool(pool)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        default_task.await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        for _ in 0..2 {
+            let res = client
+                .post(format!("http://{}", relay_addr))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.bytes().await.unwrap(), Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        }
+        relayed.assert_async().await;
+
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_gateway_pool_denied_member() {
+        init_crypto_provider();
+        let mut default_gateway = mockito::Server::new_async().await;
+        let mut member = mockito::Server::new_async().await;
+        let mut relayed = Vec::new();
+        for (server, expected) in [(&mut default_gateway, 4), (&mut member, 0)] {
+            server
+                .mock("GET", "/.well-known/ohttp-gateway")
+                .with_header("content-type", "application/ohttp-keys")
+                .with_body(Vec::from_hex(OHTTP_KEYS).unwrap())
+                .create_async()
+                .await;
+            let mock = server
+                .mock("POST", "/.well-known/ohttp-gateway")
+                .with_header("content-type", "message/ohttp-res")
+                .with_body(Vec::from_hex(ENCAPSULATED_RES).unwrap())
+                .expect(expected)
+                .create_async()
+                .await;
+            relayed.push(mock);
+        }
+
+        let pool = GatewayPoolConfig {
+            members: vec![(GatewayUri::from_str(&member.url()).unwrap(), 100)],
+            ..GatewayPoolConfig::default()
+        };
+        let lists = GatewayLists::new([], [member.host_with_port().as_str()]).unwrap();
+        let relay = RelayConfig::builder(GatewayUri::from_str(&default_gateway.url()).unwrap())
+            .gateway_pool(pool)
+            .gateway_lists(lists)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        for _ in 0..4 {
+            let res = client
+                .post(format!("http://{}", relay_addr))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+        }
+        for mock in relayed {
+            mock.assert_async().await;
+        }
+
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_outbound_header_policy() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let (headers_tx, mut headers_rx) = tokio::sync::mpsc::unbounded_channel();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway(gateway_port, move |stream| {
+                let headers_tx = headers_tx.clone();
+                tokio::spawn(async move {
+                    let recording_ohttp_req = service_fn(|req: Request<Incoming>| {
+                        let _ = headers_tx.send(req.headers().clone());
+                        handle_ohttp_req(req)
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), recording_ohttp_req)
+                        .await;
+                });
+            })
+            .await;
+        });
+
+        let policy = HeaderPolicy::default()
+            .header(hyper::header::AUTHORIZATION, HeaderValue::from_static("Bearer relay-token"))
+            .unwrap();
+        let relay = RelayConfig::builder(gateway)
+            .header_policy(policy)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let res = reqwest::Client::builder()
+            .no_proxy()
+            .user_agent("client/1.0")
+            .build()
+            .unwrap()
+            .post(format!("http://{}/", relay_addr))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .header("cookie", "id=client")
+            .header("forwarded", "for=192.0.2.1")
+            .header("x-forwarded-for", "192.0.2.1")
+            .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+
+        let headers = headers_rx.recv().await.expect("gateway should receive the request");
+        let mut names = headers.keys().map(|name| name.as_str()).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            ["authorization", "content-length", "content-type", "host", "user-agent"]
+        );
+        assert_eq!(headers["user-agent"], header_policy::DEFAULT_USER_AGENT);
+        assert_eq!(headers["authorization"], "Bearer relay-token");
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+        let relay = RelayConfig::builder(gateway)
+            .rate_limits(RateLimits {
+                relay: Some(RateLimit { burst: 1, per_second: 0.1 }),
+                ..RateLimits::default()
+            })
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let relay_req = || {
+            client
+                .post(format!("http://{}/", relay_addr))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+        };
+        assert_eq!(relay_req().await.unwrap().status(), 200);
+        let res = relay_req().await.unwrap();
+        assert_eq!(res.status(), 429, "second request should exceed the burst");
+        assert_eq!(res.headers().get("retry-after").unwrap(), "10");
+
+        let health = client.get(format!("http://{}/health", relay_addr)).send().await.unwrap();
+        assert_eq!(health.status(), 200, "health checks should not be rate limited");
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_proxy_protocol() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+        let relay = RelayConfig::builder(gateway)
+            .rate_limits(RateLimits {
+                relay: Some(RateLimit { burst: 1, per_second: 0.1 }),
+                ..RateLimits::default()
+            })
+            .proxy_protocol(true)
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let relay_req = |source: &'static str| async move {
+            let mut stream = TcpStream::connect(relay_addr).await.unwrap();
+            let req = Vec::from_hex(ENCAPSULATED_REQ).unwrap();
+            let head = format!(
+                "PROXY TCP4 {} 127.0.0.1 50000 3000\r\n\
+                 POST / HTTP/1.1\r\nHost: relay\r\nContent-Type: message/ohttp-req\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                source,
+                req.len()
+            );
+            stream.write_all(&[head.as_bytes(), &req].concat()).await.unwrap();
+            let mut res = Vec::new();
+            stream.read_to_end(&mut res).await.unwrap();
+            String::from_utf8_lossy(&res[..12]).to_string()
+        };
+        assert_eq!(relay_req("192.0.2.1").await, "HTTP/1.1 200");
+        assert_eq!(relay_req("192.0.2.2").await, "HTTP/1.1 200", "sources are limited separately");
+        assert_eq!(relay_req("192.0.2.1").await, "HTTP/1.1 429");
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_limits() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_slow(gateway_port, std::time::Duration::from_millis(500)).await;
+        });
+        let relay = RelayConfig::builder(gateway)
+            .concurrency_limits(ConcurrencyLimits {
+                connections: Some(2),
+                in_flight_requests: Some(1),
+                tunnels: Some(1),
+            })
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let relay_req = || {
+            reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .unwrap()
+                .post(format!("http://{}/", relay_addr))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send()
+        };
+        let in_flight = tokio::spawn(relay_req());
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(relay.load(), Load { connections: 1, in_flight_requests: 1, tunnels: 0 });
+
+        let res = relay_req().await.unwrap();
+        assert_eq!(res.status(), 503, "in-flight requests past the cap should be shed");
+        assert_eq!(res.headers().get("retry-after").unwrap(), "5");
+
+        let _idle = TcpStream::connect(relay_addr).await.unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let res = relay_req().await.unwrap();
+        assert_eq!(res.status(), 503, "connections past the cap should be shed");
+
+        assert_eq!(in_flight.await.unwrap().unwrap().status(), 200);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(relay.load().in_flight_requests, 0);
+
+        relay.shutdown();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_accept_error() {
+        use std::task::{Context, Poll};
+
+        /// Fails to accept once, as when out of file descriptors.
+        struct FailingOnce {
+            listener: TcpListener,
+            failed: bool,
+        }
+
+        impl tokio_util::net::Listener for FailingOnce {
+            type Io = TcpStream;
+            type Addr = SocketAddr;
+
+            fn poll_accept(
+                &mut self,
+                cx: &mut Context<'_>,
+            ) -> Poll<std::io::Result<(TcpStream, SocketAddr)>> {
+                if !std::mem::replace(&mut self.failed, true) {
+                    return Poll::Ready(Err(std::io::Error::other("too many open files")));
+                }
+                self.listener.poll_accept(cx)
+            }
+
+            fn local_addr(&self) -> std::io::Result<SocketAddr> { self.listener.local_addr() }
+        }
+
+        init_crypto_provider();
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await.unwrap();
+        let relay_addr = listener.local_addr().unwrap();
+        let relay = RelayConfig::builder(GatewayUri::from_str("http://0.0.0.0:1").unwrap())
+            .build()
+            .serve(FailingOnce { listener, failed: false })
+            .await
+            .expect("relay should start");
+
+        let res = reqwest::Client::builder()
+            .no_proxy()
+            .build()
+            .unwrap()
+            .get(format!("http://{}/health", relay_addr))
+            .send()
+            .await
+            .expect("relay should keep accepting after an accept error");
+        assert_eq!(res.status(), 200);
+
+        relay.shutdown();
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_http(gateway_port).await;
+        });
+        let config = RelayConfig::builder(gateway.clone())
+            .metrics_endpoint(true)
+            .allow_private_gateways(true)
+            .build();
+        let admin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let admin_addr = admin.local_addr().unwrap();
+        let metrics = config.metrics();
+        let relay = config
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let admin_task = metrics.serve(admin, relay.shutdown_token());
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let client = reqwest::Client::builder().no_proxy().build().unwrap();
+        let res = client
+            .post(format!("http://{}/", relay_addr))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        // a gateway nobody listens on, which must be probed and is not labeled
+        let unreachable = format!("http://127.0.0.1:{}", find_free_port());
+        let res = client
+            .post(format!("http://{}/{}", relay_addr, unreachable))
+            .header(CONTENT_TYPE, "message/ohttp-req")
+            .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 503);
+
+        for metrics_addr in [relay_addr, admin_addr] {
+            let body = client
+                .get(format!("http://{}/metrics", metrics_addr))
+                .send()
+                .await
+                .unwrap()
+                .text()
+                .await
+                .unwrap();
+            assert!(body.contains(&format!(
+                r#"ohttp_relay_requests_total{{gateway="{}",outcome="relayed",status="200"}} 1"#,
+                gateway.to_uri()
+            )));
+            assert!(body.contains(
+                r#"ohttp_relay_requests_total{gateway="other",outcome="failed",status="503"} 1"#
+            ));
+            assert!(body.contains(r#"ohttp_relay_prober_cache_total{result="hit"} 1"#));
+            assert!(body.contains(r#"ohttp_relay_prober_cache_total{result="miss"} 1"#));
+            assert!(body.contains(r#"ohttp_relay_probes_total{category="default"} 1"#));
+            assert!(!body.contains(&unreachable), "unlabeled gateways should not be exposed");
+        }
+
+        relay.shutdown();
+        admin_task.await.unwrap();
+        gateway_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_requests() {
+        init_crypto_provider();
+        let gateway_port = find_free_port();
+        let gateway = GatewayUri::from_str(&format!("http://127.0.0.1:{}", gateway_port)).unwrap();
+        let gateway_task = tokio::spawn(async move {
+            let _ = example_gateway_slow(gateway_port, std::time::Duration::from_millis(500)).await;
+        });
+
+        let relay = RelayConfig::builder(gateway)
+            .drain_timeout(std::time::Duration::from_secs(5))
+            .build()
+            .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .expect("Failed to listen on free port");
+        let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let in_flight = tokio::spawn(
+            reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .unwrap()
+                .post(format!("http://{}/", relay_addr))
+                .header(CONTENT_TYPE, "message/ohttp-req")
+                .body(Vec::from_hex(ENCAPSULATED_REQ).unwrap())
+                .send(),
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        relay.shutdown();
+
+        let res = in_flight.await.unwrap().expect("in-flight request should complete");
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.bytes().await.unwrap().as_ref(), Vec::from_hex(ENCAPSULATED_RES).unwrap());
+        tokio::time::timeout(std::time::Duration::from_secs(5), relay)
+            .await
+            .expect("relay should stop after draining")
+            .expect("relay should stop without error");
+        assert!(
+            TcpStream::connect(relay_addr).await.is_err(),
+            "relay should not accept connections after shutdown"
+        );
+        gateway_task.abort();
+    }
+
     async fn example_gateway_http(port: u16) -> Result<(), Box<dyn std::error::Error>> {
         example_gateway(port, |stream| {
             tokio::spawn(async move {
@@ -131,6 +1286,50 @@ mod integration {
         .await
     }
 
+    /// A gateway which delays every OHTTP response.
+    async fn example_gateway_slow(
+        port: u16,
+        delay: std::time::Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        example_gateway(port, move |stream| {
+            tokio::spawn(async move {
+                let slow_ohttp_req = service_fn(|req| async move {
+                    tokio::time::sleep(delay).await;
+                    handle_ohttp_req(req).await
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), slow_ohttp_req)
+                    .await;
+            });
+        })
+        .await
+    }
+
+    /// A gateway which responds to OHTTP requests with the given status and
+    /// content type, and metadata which must not reach the client.
+    async fn example_gateway_leaky(
+        port: u16,
+        status: u16,
+        content_type: &'static str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        example_gateway(port, move |stream| {
+            tokio::spawn(async move {
+                let leaky_ohttp_req = service_fn(|_| async move {
+                    let mut res = Response::new(full(Vec::from_hex(ENCAPSULATED_RES).unwrap()));
+                    *res.status_mut() = hyper::StatusCode::from_u16(status).unwrap();
+                    res.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+                    res.headers_mut().insert("set-cookie", HeaderValue::from_static("id=gateway"));
+                    res.headers_mut().insert("server", HeaderValue::from_static("gateway/1.0"));
+                    Ok::<_, hyper::Error>(res)
+                });
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), leaky_ohttp_req)
+                    .await;
+            });
+        })
+        .await
+    }
+
     async fn handle_gateway(
         req: Request<Incoming>,
     ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
@@ -237,10 +1436,9 @@ mod integration {
 
         use super::*;
 
-        const OHTTP_KEYS: &str = "01002031e1f05a740102115220e9af918f738674aec95f54db6e04eb705aae8e79815500080001000100010003";
-
         #[cfg(feature = "ws-bootstrap")]
         mod ws_bootstrap {
+            use ohttp_relay::bootstrap::ws::{WsIo, SUBPROTOCOL};
             use tokio_tungstenite::connect_async;
 
             use super::*;
@@ -254,13 +1452,96 @@ mod integration {
                 .await;
             }
 
+            #[tokio::test]
+            async fn test_ws_bootstrap_policy() {
+                use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+                use tokio_tungstenite::tungstenite::Error as WsError;
+
+                init_crypto_provider();
+                let gateway_port = find_free_port();
+                let gateway =
+                    GatewayUri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+                let gateway_cert = gen_localhost_cert();
+                let gateway_cert_der = cert_to_cert_der(&gateway_cert);
+                let gateway_task = tokio::spawn(async move {
+                    let _ = example_gateway_https(gateway_port, gateway_cert).await;
+                });
+                let policy = WebSocketPolicy {
+                    allowed_origins: Some(vec!["https://wallet.example".to_string()]),
+                    ..WebSocketPolicy::default()
+                };
+                let relay = RelayConfig::builder(gateway.clone())
+                    .websocket_policy(policy)
+                    .build()
+                    .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+                    .await
+                    .expect("Failed to listen on free port");
+                let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+                let connect = |origin: &'static str, protocol: &'static str| {
+                    let url = format!("ws://{}/{}", relay_addr, gateway.to_uri());
+                    let mut req = url.into_client_request().unwrap();
+                    req.headers_mut().insert("origin", HeaderValue::from_static(origin));
+                    req.headers_mut()
+                        .insert("sec-websocket-protocol", HeaderValue::from_static(protocol));
+                    connect_async(req)
+                };
+                let status = |result: Result<_, WsError>| match result {
+                    Err(WsError::Http(res)) => res.status(),
+                    Err(e) => panic!("expected an HTTP error, got {}", e),
+                    Ok(_) => panic!("expected the upgrade to be refused"),
+                };
+
+                let refused = connect("https://evil.example", SUBPROTOCOL).await;
+                assert_eq!(status(refused), hyper::StatusCode::FORBIDDEN);
+                let refused = connect("https://wallet.example", "chat").await;
+                assert_eq!(status(refused), hyper::StatusCode::BAD_REQUEST);
+                let url = format!("ws://{}/{}", relay_addr, gateway.to_uri());
+                let refused = connect_async(url).await;
+                assert_eq!(
+                    status(refused),
+                    hyper::StatusCode::BAD_REQUEST,
+                    "the subprotocol should be required when origins are restricted"
+                );
+
+                let (ws_stream, res) =
+                    connect("https://wallet.example", SUBPROTOCOL).await.unwrap();
+                assert_eq!(
+                    res.headers().get("sec-websocket-protocol").unwrap(),
+                    SUBPROTOCOL,
+                    "the subprotocol should be echoed"
+                );
+                let mut root_store = rustls::RootCertStore::empty();
+                root_store.add(gateway_cert_der).unwrap();
+                let config = tokio_rustls::rustls::ClientConfig::builder()
+                    .with_root_certificates(root_store)
+                    .with_no_client_auth();
+                let domain = pki_types::ServerName::try_from("0.0.0.0").unwrap().to_owned();
+                let mut tls_stream = TlsConnector::from(Arc::new(config))
+                    .connect(domain, WsIo::new(ws_stream))
+                    .await
+                    .unwrap();
+                tls_stream
+                    .write_all(
+                        b"GET /.well-known/ohttp-gateway HTTP/1.1\r\nHost: 0.0.0.0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                tls_stream.flush().await.unwrap();
+                let mut response = Vec::new();
+                let _ = tls_stream.read_to_end(&mut response).await;
+                assert!(response.starts_with(b"HTTP/1.1 200"), "keys should be fetched");
+
+                relay.shutdown();
+                gateway_task.abort();
+            }
+
             async fn ohttp_keys_ws_client(
                 relay_port: u16,
                 gateway: GatewayUri,
                 cert: CertificateDer<'_>,
             ) {
-                use ohttp_relay::bootstrap::ws::WsIo;
-
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
                 let mut root_store = rustls::RootCertStore::empty();
@@ -307,6 +1588,117 @@ mod integration {
                 .await;
             }
 
+            #[tokio::test]
+            async fn test_connect_bootstrap_http2() {
+                init_crypto_provider();
+                let gateway_port = find_free_port();
+                let gateway =
+                    GatewayUri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+                let gateway_cert = gen_localhost_cert();
+                let gateway_cert_der = cert_to_cert_der(&gateway_cert);
+                let gateway_task = tokio::spawn(async move {
+                    let _ = example_gateway_https(gateway_port, gateway_cert).await;
+                });
+
+                let relay = RelayConfig::builder(gateway.clone())
+                    .build()
+                    .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+                    .await
+                    .expect("Failed to listen on free port");
+                let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+                let stream = TcpStream::connect(relay_addr).await.unwrap();
+                let (mut sender, conn) = hyper::client::conn::http2::handshake(
+                    TokioExecutor::new(),
+                    TokioIo::new(stream),
+                )
+                .await
+                .expect("relay should accept HTTP/2 with prior knowledge");
+                tokio::spawn(conn);
+
+                let mut req = Request::new(full(Bytes::new()));
+                *req.method_mut() = hyper::Method::CONNECT;
+                *req.uri_mut() = format!("0.0.0.0:{}", gateway_port).parse().unwrap();
+                let res = sender.send_request(req).await.unwrap();
+                assert_eq!(res.status(), hyper::StatusCode::OK);
+                let upgraded = hyper::upgrade::on(res).await.expect("CONNECT should upgrade");
+
+                let mut root_store = rustls::RootCertStore::empty();
+                root_store.add(gateway_cert_der).unwrap();
+                let config = rustls::ClientConfig::builder()
+                    .with_root_certificates(root_store)
+                    .with_no_client_auth();
+                let domain = pki_types::ServerName::try_from("0.0.0.0").unwrap().to_owned();
+                let mut tls_stream = TlsConnector::from(Arc::new(config))
+                    .connect(domain, TokioIo::new(upgraded))
+                    .await
+                    .expect("TLS handshake with gateway should succeed through the tunnel");
+
+                tls_stream
+                    .write_all(
+                        b"GET /.well-known/ohttp-gateway HTTP/1.1\r\nHost: 0.0.0.0\r\nConnection: close\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let mut plaintext = Vec::new();
+                let _ = tls_stream.read_to_end(&mut plaintext).await;
+                let plaintext = String::from_utf8_lossy(&plaintext);
+                assert!(plaintext.starts_with("HTTP/1.1 200 OK"), "{}", plaintext);
+                assert!(plaintext.contains("application/ohttp-keys"), "{}", plaintext);
+
+                relay.shutdown();
+                gateway_task.abort();
+            }
+
+            #[tokio::test]
+            async fn test_connect_bootstrap_requires_tls() {
+                init_crypto_provider();
+                let gateway_port = find_free_port();
+                let gateway =
+                    GatewayUri::from_str(&format!("https://0.0.0.0:{}", gateway_port)).unwrap();
+                let gateway_cert = gen_localhost_cert();
+                let gateway_task = tokio::spawn(async move {
+                    let _ = example_gateway_https(gateway_port, gateway_cert).await;
+                });
+                let relay = RelayConfig::builder(gateway)
+                    .build()
+                    .listen_tcp(SocketAddr::from(([127, 0, 0, 1], 0)))
+                    .await
+                    .expect("Failed to listen on free port");
+                let relay_addr = relay.local_addr().expect("TCP relay should have a local address");
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+                let connect = |authority: String| async move {
+                    let mut stream = TcpStream::connect(relay_addr).await.unwrap();
+                    let req = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority);
+                    stream.write_all(req.as_bytes()).await.unwrap();
+                    let mut head = [0; 12];
+                    stream.read_exact(&mut head).await.unwrap();
+                    (stream, String::from_utf8_lossy(&head).into_owned())
+                };
+
+                let (_, status) = connect("0.0.0.0".to_string()).await;
+                assert_eq!(status, "HTTP/1.1 400", "CONNECT must name the gateway's port");
+
+                let (mut stream, status) = connect(format!("0.0.0.0:{}", gateway_port)).await;
+                assert_eq!(status, "HTTP/1.1 200");
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                stream
+                    .write_all(b"GET /.well-known/ohttp-gateway HTTP/1.1\r\nHost: 0.0.0.0\r\n\r\n")
+                    .await
+                    .unwrap();
+                let mut rest = Vec::new();
+                let _ = stream.read_to_end(&mut rest).await;
+                assert!(rest.is_empty(), "plaintext should not be tunneled to the gateway");
+
+                relay.shutdown();
+                gateway_task.abort();
+            }
+
             async fn ohttp_keys_connect_client(
                 relay_port: u16,
                 gateway: GatewayUri,